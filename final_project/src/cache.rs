@@ -0,0 +1,504 @@
+// The response cache: its entries and file formats, where it lives, and the bookkeeping that keeps
+// identical requests running side by side from being sent twice
+use super::*;
+
+pub(crate) const DEFAULT_CACHE_LIMIT: usize = 10;
+#[cfg(feature = "async")]
+pub(crate) const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(50); // How often an async request checks on an identical one
+pub(crate) const CACHE_FILE: &str = "api_cache.json";
+pub(crate) const CACHE_FILE_NAME: &str = "cache.jsonl";
+pub(crate) const COMPRESSED_CACHE_FILE: &str = "api_cache.json.gz";
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+// The cache file is rewritten once it holds more than twice its live records plus this many
+pub(crate) const CACHE_COMPACTION_SLACK: usize = 100;
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct CacheEntry {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) prompt: String, // Kept for debugging only: the full prompt, a preview of it, or nothing, per CACHE_PROMPT_STORAGE
+    pub(crate) response: String,
+    #[serde(default)]
+    pub(crate) namespace: String, // The project the entry was cached for; empty for entries from older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) prompt_hash: Option<String>, // The lookup key; older entries only have the prompt and are hashed on load
+    #[serde(default)]
+    pub(crate) last_used: u64, // UNIX timestamp of the last hit or insert; 0 for entries migrated from older versions
+    #[serde(default)]
+    pub(crate) created_at: u64, // UNIX timestamp of the insert; 0 for older entries, which count as expired once a TTL is set
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub(crate) struct Cache {
+    pub(crate) entries: Vec<CacheEntry>,
+    #[serde(skip)]
+    pub(crate) namespace: String, // The project this session looks up and stores entries under
+    #[serde(skip)]
+    pub(crate) limit: usize, // Maximum number of entries before the least recently used one is evicted
+    #[serde(skip)]
+    pub(crate) prompt_preview_chars: Option<usize>, // When set, only this many characters of each prompt are stored
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) failures: Vec<FailureEntry>, // Recent non-retryable failures, kept apart from successful entries
+    #[serde(skip)]
+    pub(crate) failure_ttl_secs: Option<u64>, // How long a failure is replayed; None unless CACHE_FAILURES=true
+    #[serde(skip)]
+    pub(crate) index: HashMap<(String, String), usize>, // (namespace, prompt hash) to position in `entries`
+    #[serde(skip)]
+    pub(crate) ttl_secs: Option<u64>, // Entries older than this are misses; None keeps them until evicted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_language: Option<String>, // Offered as the default language on the next launch
+    #[serde(skip)]
+    pub(crate) dirty: bool, // Entries or failures changed since the cache file was last written
+    #[serde(skip)]
+    pub(crate) journal: Vec<CacheRecord>, // Changes not yet appended to the cache file
+    #[serde(skip)]
+    pub(crate) needs_rewrite: bool, // Something was removed, which appending can't express, so the next save rewrites the file
+    #[serde(skip)]
+    pub(crate) records_on_disk: usize, // Lines in the cache file, superseded ones included
+}
+
+// A line of the cache file. Later lines win: an entry replaces one with the same project and hash,
+// and a touch moves an entry's last use forward
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CacheRecord {
+    Entry(CacheEntry),
+    Touch { namespace: String, prompt_hash: String, last_used: u64 },
+    Failure(FailureEntry),
+    LastLanguage(String),
+}
+
+// A request that failed in a way retrying won't fix, such as a content filter rejection
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct FailureEntry {
+    pub(crate) prompt_hash: String,
+    pub(crate) namespace: String,
+    pub(crate) message: String,
+    pub(crate) failed_at: u64, // UNIX timestamp in seconds
+}
+
+impl FailureEntry {
+    // Failures are kept per project, like entries, so one project's failure never hides another's
+    pub(crate) fn same_request(&self, other: &FailureEntry) -> bool {
+        self.namespace == other.namespace && self.prompt_hash == other.prompt_hash
+    }
+
+    // Without CACHE_FAILURES (no `ttl`) no failure is replayed, so none is worth keeping
+    pub(crate) fn is_live(&self, ttl: Option<u64>, now: u64) -> bool {
+        ttl.is_some_and(|ttl| now.saturating_sub(self.failed_at) < ttl)
+    }
+}
+
+impl Cache {
+    // The settings that aren't stored in the file: project, size limit, prompt storage and expiry
+    pub(crate) fn configure(&mut self, config: &Config) {
+        self.namespace = project_namespace(config);
+        self.limit = cache_limit(config);
+        self.prompt_preview_chars = prompt_preview_chars(config);
+        self.ttl_secs = config.value("CACHE_TTL_SECS").and_then(|ttl| ttl.trim().parse().ok());
+        if config.flag("CACHE_FAILURES") {
+            self.failure_ttl_secs = Some(config.setting("FAILURE_CACHE_TTL_SECS", 300));
+        }
+        self.prune_failures();
+    }
+
+    pub(crate) fn add_entry(&mut self, prompt: String, response: String) {
+        // An empty answer would be served forever instead of asking again
+        if response.trim().is_empty() {
+            return;
+        }
+        if self.entries.len() >= self.limit {
+            self.evict_least_recently_used();
+            self.needs_rewrite = true;
+        }
+        let prompt_hash = Some(prompt_hash(&prompt));
+        let prompt = match self.prompt_preview_chars {
+            Some(chars) => prompt.chars().take(chars).collect(),
+            None => prompt,
+        };
+        let namespace = self.namespace.clone();
+        let last_used = now_secs();
+        if let Some(hash) = &prompt_hash {
+            self.index.insert((namespace.clone(), hash.clone()), self.entries.len());
+        }
+        let entry = CacheEntry { prompt, response, namespace, prompt_hash, last_used, created_at: last_used };
+        self.journal.push(CacheRecord::Entry(entry.clone()));
+        self.entries.push(entry); // Adds the new entry to the end
+        self.dirty = true;
+    }
+
+    pub(crate) fn set_last_language(&mut self, language: &str) {
+        if self.last_language.as_deref() != Some(language) {
+            self.last_language = Some(language.to_string());
+            self.journal.push(CacheRecord::LastLanguage(language.to_string()));
+            self.dirty = true;
+        }
+    }
+
+    // Every live record, which is what a rewritten cache file holds
+    pub(crate) fn records(&self) -> Vec<CacheRecord> {
+        let entries = self.entries.iter().cloned().map(CacheRecord::Entry);
+        let now = now_secs();
+        let failures = self.failures.iter().filter(|failure| failure.is_live(self.failure_ttl_secs, now)).cloned().map(CacheRecord::Failure);
+        let language = self.last_language.clone().map(CacheRecord::LastLanguage);
+        entries.chain(failures).chain(language).collect()
+    }
+
+    // Replays the cache file's lines in order
+    pub(crate) fn from_records(records: Vec<CacheRecord>) -> Cache {
+        let mut cache = Cache { records_on_disk: records.len(), ..Default::default() };
+        let mut positions: HashMap<(String, String), usize> = HashMap::new();
+        for record in records {
+            match record {
+                CacheRecord::Entry(entry) => {
+                    let key = entry.prompt_hash.clone().map(|hash| (entry.namespace.clone(), hash));
+                    match key.as_ref().and_then(|key| positions.get(key)) {
+                        Some(&position) => cache.entries[position] = entry,
+                        None => {
+                            if let Some(key) = key {
+                                positions.insert(key, cache.entries.len());
+                            }
+                            cache.entries.push(entry);
+                        }
+                    }
+                }
+                CacheRecord::Touch { namespace, prompt_hash, last_used } => {
+                    if let Some(&position) = positions.get(&(namespace, prompt_hash)) {
+                        cache.entries[position].last_used = last_used;
+                    }
+                }
+                CacheRecord::Failure(failure) => {
+                    cache.failures.retain(|earlier| !failure.same_request(earlier));
+                    cache.failures.push(failure);
+                }
+                CacheRecord::LastLanguage(language) => cache.last_language = Some(language),
+            }
+        }
+        cache
+    }
+
+    // Entries saved before prompts were hashed get their hash here, so every entry is reachable through the index
+    pub(crate) fn rebuild_index(&mut self) {
+        for entry in &mut self.entries {
+            if entry.prompt_hash.is_none() {
+                entry.prompt_hash = Some(prompt_hash(&entry.prompt));
+            }
+        }
+        self.index = self.entries.iter()
+            .enumerate()
+            .filter_map(|(position, entry)| Some(((entry.namespace.clone(), entry.prompt_hash.clone()?), position)))
+            .collect();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+        self.needs_rewrite = true;
+        self.dirty = true;
+    }
+
+    pub(crate) fn remove(&mut self, position: usize) -> CacheEntry {
+        let entry = self.entries.remove(position);
+        self.rebuild_index();
+        self.needs_rewrite = true;
+        self.dirty = true;
+        entry
+    }
+
+    // Ties (such as migrated entries, which all have last_used 0) go to the oldest inserted entry
+    pub(crate) fn evict_least_recently_used(&mut self) {
+        let least_recent = self.entries.iter()
+            .enumerate()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(index, _)| index);
+        if let Some(index) = least_recent {
+            self.entries.remove(index);
+            self.rebuild_index();
+        }
+    }
+
+    // Only unexpired entries cached for the current project can be hits; a hit counts as a use for eviction
+    pub(crate) fn lookup(&mut self, prompt: &str) -> Option<&CacheEntry> {
+        let key = (self.namespace.clone(), prompt_hash(prompt));
+        let position = match self.index.get(&key) {
+            Some(&position) => position,
+            None => {
+                // Entries keyed before SHA-256 carry a 64-bit FNV-1a hash; they are rekeyed on their first hit
+                let legacy_key = (self.namespace.clone(), format!("{:016x}", fnv1a_hash(prompt)));
+                let position = self.index.remove(&legacy_key)?;
+                self.entries[position].prompt_hash = Some(key.1.clone());
+                self.index.insert(key, position);
+                self.needs_rewrite = true;
+                position
+            }
+        };
+        let now = now_secs();
+        // Empty entries, as older versions could store, are dropped like expired ones
+        let expired = self.ttl_secs.is_some_and(|ttl| now.saturating_sub(self.entries[position].created_at) >= ttl);
+        if expired || self.entries[position].response.trim().is_empty() {
+            self.entries.remove(position);
+            self.rebuild_index();
+            self.needs_rewrite = true;
+            self.dirty = true;
+            return None;
+        }
+        let entry = &mut self.entries[position];
+        entry.last_used = now;
+        if let Some(prompt_hash) = entry.prompt_hash.clone() {
+            self.journal.push(CacheRecord::Touch { namespace: entry.namespace.clone(), prompt_hash, last_used: now });
+            self.dirty = true;
+        }
+        Some(&self.entries[position])
+    }
+
+    pub(crate) fn record_failure(&mut self, prompt: &str, message: String) {
+        if self.failure_ttl_secs.is_none() {
+            return;
+        }
+        let now = now_secs();
+        let failure = FailureEntry { prompt_hash: prompt_hash(prompt), namespace: self.namespace.clone(), message, failed_at: now };
+        self.failures.retain(|earlier| !failure.same_request(earlier));
+        self.prune_failures();
+        self.journal.push(CacheRecord::Failure(failure.clone()));
+        self.failures.push(failure);
+        self.dirty = true;
+    }
+
+    // A failure for this prompt that is still within its TTL, if failure caching is enabled
+    pub(crate) fn recent_failure(&self, prompt: &str) -> Option<&FailureEntry> {
+        let hash = prompt_hash(prompt);
+        let now = now_secs();
+        self.failures.iter().find(|failure| {
+            failure.namespace == self.namespace && failure.prompt_hash == hash && failure.is_live(self.failure_ttl_secs, now)
+        })
+    }
+
+    // Drops expired failures, which would otherwise pile up in the file; the next save rewrites it without them
+    pub(crate) fn prune_failures(&mut self) {
+        let (ttl, now) = (self.failure_ttl_secs, now_secs());
+        let before = self.failures.len();
+        self.failures.retain(|failure| failure.is_live(ttl, now));
+        if self.failures.len() != before {
+            self.needs_rewrite = true;
+            self.dirty = true;
+        }
+    }
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+// Hex SHA-256 of a prompt, the key cache entries and failures are stored under
+pub(crate) fn prompt_hash(prompt: &str) -> String {
+    format!("{:x}", Sha256::digest(prompt.as_bytes()))
+}
+
+// The cache keys of requests being sent, so identical concurrent requests go out only once
+#[derive(Default)]
+pub(crate) struct InFlight {
+    pub(crate) keys: Mutex<HashSet<String>>,
+    pub(crate) finished: Condvar,
+}
+
+impl InFlight {
+    // Claims `key` for this caller, or, when someone else holds it, waits until they are done and returns None
+    pub(crate) fn claim(in_flight: &Arc<InFlight>, key: &str) -> Option<InFlightClaim> {
+        let mut keys = in_flight.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if keys.insert(key.to_string()) {
+            return Some(InFlightClaim { in_flight: Arc::clone(in_flight), key: key.to_string() });
+        }
+        while keys.contains(key) {
+            keys = in_flight.finished.wait(keys).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        None
+    }
+
+    // Claims `key` without waiting, for async callers that must not block their thread; None while it is held
+    #[cfg(feature = "async")]
+    pub(crate) fn try_claim(in_flight: &Arc<InFlight>, key: &str) -> Option<InFlightClaim> {
+        let mut keys = in_flight.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        keys.insert(key.to_string()).then(|| InFlightClaim { in_flight: Arc::clone(in_flight), key: key.to_string() })
+    }
+}
+
+// Releases its key when dropped, however the request ended, and wakes everyone waiting on it
+pub(crate) struct InFlightClaim {
+    pub(crate) in_flight: Arc<InFlight>,
+    pub(crate) key: String,
+}
+
+impl Drop for InFlightClaim {
+    fn drop(&mut self) {
+        self.in_flight.keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&self.key);
+        self.in_flight.finished.notify_all();
+    }
+}
+
+pub(crate) fn load_cache(filename: &str) -> Result<Cache, AssistantError> {
+    if !Path::new(filename).exists() {
+        return Ok(Cache::default()); // If the file doesn't exist, return an empty cache
+    }
+    let mut cache = match read_cache_file(filename).and_then(|content| parse_cache(&content)) {
+        Ok(cache) => cache,
+        // A truncated or hand-edited file is set aside instead of keeping the assistant from starting
+        Err(AssistantError::Parse(error)) => {
+            let backup = format!("{}.bak", filename);
+            match fs::rename(filename, &backup) {
+                Ok(()) => warn!("{} could not be read ({}). It was moved to {} and the cache starts empty.", filename, error, backup),
+                Err(rename_error) => warn!("{} could not be read ({}) or moved aside ({}). The cache starts empty.", filename, error, rename_error),
+            }
+            Cache::default()
+        }
+        // One that can't be read at all, e.g. for lack of permission, is left alone and reported
+        Err(error) => return Err(error),
+    };
+    cache.rebuild_index();
+    Ok(cache)
+}
+
+// The cache file is JSON lines, one record each. Files from older versions hold a single JSON document,
+// a Cache or before that a HashMap of prompts to responses; they are read as well and rewritten as lines
+pub(crate) fn parse_cache(content: &str) -> Result<Cache, AssistantError> {
+    let records: Result<Vec<CacheRecord>, _> = content.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect();
+    let error = match records {
+        Ok(records) => return Ok(Cache::from_records(records)),
+        Err(error) => error,
+    };
+    // Then the Cache structure, and then the old HashMap format; if all fail, the current format's
+    // error is the one worth reporting
+    if let Ok(cache) = serde_json::from_str::<Cache>(content) {
+        return Ok(Cache { needs_rewrite: true, ..cache });
+    }
+    let old_cache: HashMap<String, String> = serde_json::from_str(content).map_err(|_| error)?;
+    let entries = old_cache.into_iter()
+        .map(|(prompt, response)| CacheEntry { prompt, response, namespace: String::new(), prompt_hash: None, last_used: 0, created_at: 0 })
+        .collect();
+    Ok(Cache { entries, needs_rewrite: true, ..Default::default() })
+}
+
+// Reads the cache file, transparently decompressing it when it starts with the gzip magic bytes
+pub(crate) fn read_cache_file(filename: &str) -> Result<String, AssistantError> {
+    let bytes = fs::read(filename)?;
+    if bytes.starts_with(&GZIP_MAGIC) {
+        let mut content = String::new();
+        // Each save appends a gzip member of its own. The bytes are already in memory, so a failure
+        // here means the file is damaged
+        MultiGzDecoder::new(bytes.as_slice()).read_to_string(&mut content)
+            .map_err(|error| AssistantError::Parse(format!("the compressed cache is damaged ({})", error)))?;
+        Ok(content)
+    } else {
+        String::from_utf8(bytes).map_err(|error| AssistantError::Parse(error.to_string()))
+    }
+}
+
+// Appends the changes since the last save as lines. The file is rewritten from scratch instead when entries were
+// removed or evicted, when it doesn't exist yet, and once superseded lines outnumber the live ones
+pub(crate) fn save_cache(filename: &str, cache: &mut Cache) -> Result<(), AssistantError> {
+    if let Some(directory) = Path::new(filename).parent().filter(|directory| !directory.as_os_str().is_empty()) {
+        fs::create_dir_all(directory)?;
+    }
+    let live = cache.entries.len() + cache.failures.len() + 1;
+    let rewrite = cache.needs_rewrite
+        || !Path::new(filename).exists()
+        || cache.records_on_disk + cache.journal.len() > 2 * live + CACHE_COMPACTION_SLACK;
+    let records = if rewrite { cache.records() } else { std::mem::take(&mut cache.journal) };
+    if rewrite || !records.is_empty() {
+        let mut content = String::new();
+        for record in &records {
+            content.push_str(&serde_json::to_string(record)?);
+            content.push('\n');
+        }
+        let bytes = if filename.ends_with(".gz") {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(content.as_bytes())?;
+            encoder.finish()?
+        } else {
+            content.into_bytes()
+        };
+        if rewrite {
+            fs::write(filename, bytes)?;
+            cache.records_on_disk = records.len();
+        } else {
+            fs::OpenOptions::new().append(true).open(filename)?.write_all(&bytes)?;
+            cache.records_on_disk += records.len();
+        }
+    }
+    cache.journal.clear();
+    cache.needs_rewrite = false;
+    cache.dirty = false;
+    Ok(())
+}
+
+// CACHE_LIMIT, falling back to the default when unset, malformed or zero
+pub(crate) fn cache_limit(config: &Config) -> usize {
+    match config.setting("CACHE_LIMIT", DEFAULT_CACHE_LIMIT) {
+        0 => {
+            warn!("CACHE_LIMIT must be at least 1, using {}.", DEFAULT_CACHE_LIMIT);
+            DEFAULT_CACHE_LIMIT
+        }
+        limit => limit,
+    }
+}
+
+// Cache entries are scoped to a project: the PROJECT setting, or else the current directory's name
+pub(crate) fn project_namespace(config: &Config) -> String {
+    config.value("PROJECT")
+        .filter(|project| !project.trim().is_empty())
+        .map(str::to_string)
+        .or_else(|| {
+            env::current_dir()
+                .ok()
+                .and_then(|dir| dir.file_name().map(|name| name.to_string_lossy().into_owned()))
+        })
+        .unwrap_or_default()
+}
+
+// CACHE_PROMPT_STORAGE=preview stores only the first CACHE_PROMPT_PREVIEW_CHARS characters of each prompt
+// and `none` stores no prompt at all; lookups go through the prompt hash, so they keep working either way
+pub(crate) fn prompt_preview_chars(config: &Config) -> Option<usize> {
+    let storage = config.value("CACHE_PROMPT_STORAGE").unwrap_or("full");
+    if storage.eq_ignore_ascii_case("preview") {
+        Some(config.setting("CACHE_PROMPT_PREVIEW_CHARS", 200))
+    } else if storage.eq_ignore_ascii_case("none") {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+// CACHE_PATH, or cache.jsonl in the user's cache directory (e.g. ~/.cache/ai-code-assistant), falling back to
+// the working directory on systems without one. CACHE_COMPRESS adds `.gz`, which is what turns compression on
+pub(crate) fn cache_filename(config: &Config) -> String {
+    let path = match config.value("CACHE_PATH").filter(|path| !path.trim().is_empty()) {
+        Some(path) => path.trim().to_string(),
+        None => match dirs::cache_dir() {
+            Some(directory) => directory.join("ai-code-assistant").join(CACHE_FILE_NAME).display().to_string(),
+            None => CACHE_FILE_NAME.to_string(),
+        },
+    };
+    if config.flag("CACHE_COMPRESS") && !path.ends_with(".gz") {
+        format!("{}.gz", path)
+    } else {
+        path
+    }
+}
+
+// Where to read the cache from while `cache_file` doesn't exist yet: its plain form from before compression
+// was enabled, or, without CACHE_PATH, the cache.json from before the file held JSON lines and the
+// api_cache.json that older versions still kept in the working directory
+pub(crate) fn cache_source(cache_file: &str, config: &Config) -> String {
+    let legacy = config.value("CACHE_PATH").is_none();
+    let json_file = legacy.then(|| cache_file.replacen(".jsonl", ".json", 1));
+    let candidates = [
+        Some(cache_file),
+        cache_file.strip_suffix(".gz"),
+        json_file.as_deref(),
+        json_file.as_deref().and_then(|path| path.strip_suffix(".gz")),
+        legacy.then_some(COMPRESSED_CACHE_FILE),
+        legacy.then_some(CACHE_FILE),
+    ];
+    let source = candidates.into_iter().flatten().find(|path| Path::new(path).exists()).unwrap_or(cache_file);
+    source.to_string()
+}
@@ -0,0 +1,315 @@
+// The settings and the layers they come from: the environment, the .env file and config.toml
+use super::*;
+
+pub(crate) const CONFIG_FILE: &str = "config.toml";
+// Every setting the settings viewer reports, with its built-in default if it has one
+pub(crate) const KNOWN_SETTINGS: &[(&str, Option<&str>)] = &[
+    ("APP_ENV", None),
+    ("BACKEND", Some("http")),
+    ("LOCAL_COMMAND", Some("ollama run")),
+    ("LOCAL_MODEL", None),
+    ("API_ENDPOINT", None),
+    ("API_KEY", None),
+    ("AUTH_STYLE", Some("api-key")),
+    ("EXTRA_HEADERS", None),
+    ("MODEL", None),
+    ("CACHE_ENABLED", Some("true")),
+    ("CACHE_PATH", Some("~/.cache/ai-code-assistant/cache.jsonl")),
+    ("CACHE_COMPRESS", Some("false")),
+    ("CACHE_LIMIT", Some("10")),
+    ("CACHE_TTL_SECS", None),
+    ("HISTORY_LIMIT", Some("20")),
+    ("PROJECT", None),
+    ("TEMPERATURE", Some("0.7")),
+    ("TOP_P", Some("0.95")),
+    ("MAX_TOKENS", None),
+    ("SKIP_LANGUAGE_CHECK", Some("false")),
+    ("SYSTEM_PROMPT", None),
+    ("QUIET", Some("false")),
+    ("VERBOSE", Some("false")),
+    ("END_MARKER", Some("END")),
+    ("CLIPBOARD", Some("false")),
+    ("SECRET_SCAN", Some("warn")),
+    ("NO_COLOR", None),
+    ("NO_PAGER", None),
+    ("REVIEW_PROMPT", Some("true")),
+    ("HISTORY_FILE", Some(HISTORY_FILE)),
+    ("RESPONSE_FORMAT", Some("chat")),
+    ("VALIDATE_OUTPUT", Some("false")),
+    ("CONCURRENCY", Some("4")),
+    ("SUPPORTED_LANGUAGES", Some("Python,Rust,JavaScript,C++,Java,TypeScript,HTML")),
+    ("CACHE_FAILURES", Some("false")),
+    ("FAILURE_CACHE_TTL_SECS", Some("300")),
+    ("CACHE_PROMPT_STORAGE", Some("full")),
+    ("CACHE_PROMPT_PREVIEW_CHARS", Some("200")),
+    ("STREAM", Some("false")),
+    ("SESSION_RETRY_BUDGET", Some("10")),
+    ("EMPTY_RESPONSE_RETRIES", Some("1")),
+    ("MAX_REQUESTS_PER_MINUTE", None),
+    ("CONFIRM_TOKEN_THRESHOLD", Some("4000")),
+    ("PRICE_PER_1K_TOKENS", None),
+    ("MAX_IDLE_CONNECTIONS", Some("100")),
+    ("MAX_IDLE_CONNECTIONS_PER_HOST", Some("1")),
+    ("CONNECT_TIMEOUT_SECS", Some("10")),
+    ("READ_TIMEOUT_SECS", Some("60")),
+    ("PROMPTS_FILE", Some(PROMPTS_FILE)),
+    ("DRY_RUN", Some("false")),
+    ("CHOICES", Some("1")),
+    ("HTTPS_PROXY", None),
+    ("HTTP_PROXY", None),
+    ("NO_PROXY", None),
+    ("CONTEXT_LIMIT", Some("8000")),
+    ("CONTEXT_STRATEGY", Some("chunk")),
+    ("MAX_INPUT_BYTES", Some("1048576")),
+    ("MAX_LINES", None),
+    ("MAX_CHARS", None),
+    ("REVIEW_PASSES", Some("0")),
+    ("DIAGRAM_FORMAT", Some("mermaid")),
+    ("FLOW_FORMAT", None),
+];
+pub(crate) const MODE_OVERRIDE_PREFIXES: &[&str] = &["SYSTEM_PROMPT_", "MODEL_", "TEMPERATURE_", "TOP_P_", "MAX_TOKENS_", "CHOICES_"];
+pub(crate) const SECRET_SETTINGS: &[&str] = &["API_KEY"];
+
+// Where an effective setting value came from; the environment wins over the .env file, which wins over
+// config.toml, which wins over defaults. A value given with `Config::with`, e.g. from a flag, wins over them all
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SettingSource {
+    Default,
+    ConfigFile,
+    EnvFile,
+    Environment,
+    Given,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SettingValue {
+    pub(crate) name: String,
+    pub(crate) value: String,
+    pub(crate) source: SettingSource,
+}
+
+/// Settings by the names the CLI documents, such as TEMPERATURE, SYSTEM_PROMPT or CACHE_LIMIT, with the
+/// source of each value so the settings viewer can explain it. `Config::default()` holds none, so every
+/// setting keeps its built-in default; `load` reads them as the CLI does.
+#[derive(Clone, Default)]
+pub struct Config {
+    pub(crate) settings: Vec<SettingValue>,
+    pub(crate) loaded_from: Vec<String>, // The settings files that were read, for the notice once quiet mode is known
+    pub(crate) warnings: Vec<String>, // Problems with those files, printed along with it
+}
+
+impl Config {
+    /// The environment, then the `.env` file, then `config.toml` of the working directory, the first one
+    /// setting a name winning.
+    pub fn load() -> Config {
+        let mut environment: HashMap<String, String> = env::vars().collect();
+        // The proxy variables are just as often spelled in lower case
+        for name in ["HTTPS_PROXY", "HTTP_PROXY", "NO_PROXY"] {
+            if let Some(value) = environment.get(&name.to_ascii_lowercase()).filter(|_| !environment.contains_key(name)).cloned() {
+                environment.insert(name.to_string(), value);
+            }
+        }
+        let mut loaded_from = Vec::new();
+        let mut warnings = Vec::new();
+        let env_file = load_env_file(&environment, &mut loaded_from, &mut warnings);
+        let config_file = load_config_file(&mut loaded_from, &mut warnings);
+        Config { loaded_from, warnings, ..Config::from_sources(&environment, &env_file, &config_file) }
+    }
+
+    pub(crate) fn from_sources(environment: &HashMap<String, String>, env_file: &HashMap<String, String>, config_file: &HashMap<String, String>) -> Config {
+        let mut names: Vec<(String, Option<&str>)> = KNOWN_SETTINGS.iter()
+            .map(|&(name, default)| (name.to_string(), default))
+            .collect();
+        // Per-mode overrides have open-ended names, so report whichever are set
+        let overrides: HashSet<&String> = environment.keys().chain(env_file.keys()).chain(config_file.keys())
+            .filter(|name| MODE_OVERRIDE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+            .collect();
+        let mut overrides: Vec<&String> = overrides.into_iter().collect();
+        overrides.sort();
+        names.extend(overrides.into_iter().map(|name| (name.clone(), None)));
+
+        let settings = names.into_iter()
+            .filter_map(|(name, default)| resolve_setting(&name, default, environment, env_file, config_file))
+            .collect();
+        Config { settings, ..Config::default() }
+    }
+
+    /// This configuration with `name` set to `value`, over whatever it was set to before.
+    pub fn with(mut self, name: &str, value: &str) -> Config {
+        let setting = SettingValue { name: name.to_string(), value: value.to_string(), source: SettingSource::Given };
+        match self.settings.iter_mut().find(|setting| setting.name == name) {
+            Some(existing) => *existing = setting,
+            None => self.settings.push(setting),
+        }
+        self
+    }
+
+    // The value `name` is set to in any layer; None when it is left at its default, since the code that
+    // reads a setting knows its own default
+    pub(crate) fn value(&self, name: &str) -> Option<&str> {
+        self.settings.iter()
+            .find(|setting| setting.name == name && setting.source != SettingSource::Default)
+            .map(|setting| setting.value.as_str())
+    }
+
+    // True when a boolean setting is set to "true"
+    pub(crate) fn flag(&self, name: &str) -> bool {
+        self.value(name).is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+    }
+
+    // Reads a numeric setting, warning and falling back to the default when it can't be parsed
+    pub(crate) fn setting<T: FromStr + Display>(&self, name: &str, default: T) -> T {
+        match self.value(name) {
+            Some(value) => value.trim().parse().unwrap_or_else(|_| {
+                warn!("{}={} is not valid, using {}.", name, value, default);
+                default
+            }),
+            None => default,
+        }
+    }
+
+    // Like `setting` for a setting with no default: unset is None, and so is a malformed value, with a warning
+    pub(crate) fn optional_setting<T: FromStr>(&self, name: &str) -> Option<T> {
+        let value = self.value(name)?;
+        let parsed = value.trim().parse().ok();
+        if parsed.is_none() {
+            warn!("{}={} is not valid, so it is ignored.", name, value);
+        }
+        parsed
+    }
+
+    // Reads a setting that a per-mode `<NAME>_<MODE>` setting can override
+    pub(crate) fn mode_setting<T: FromStr + Display>(&self, name: &str, mode: &str, default: T) -> T {
+        let value = self.setting(name, default);
+        self.setting(&format!("{}_{}", name, mode.to_ascii_uppercase()), value)
+    }
+}
+
+// config.toml as written: setting names in either case, with a `[table]` prefixing the names of its keys,
+// so `limit = 50` under `[cache]` sets CACHE_LIMIT
+#[derive(Deserialize)]
+pub(crate) struct ConfigFile(HashMap<String, ConfigFileValue>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ConfigFileValue {
+    Text(String),
+    Integer(i64),
+    Float(f64),
+    Flag(bool),
+    Table(HashMap<String, ConfigFileValue>),
+}
+
+impl ConfigFile {
+    // The settings the file sets, by their upper-case names
+    pub(crate) fn settings(self) -> HashMap<String, String> {
+        let mut settings = HashMap::new();
+        flatten_config_table(String::new(), self.0, &mut settings);
+        settings
+    }
+}
+
+pub(crate) fn flatten_config_table(prefix: String, table: HashMap<String, ConfigFileValue>, settings: &mut HashMap<String, String>) {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key).replace(['-', '.'], "_").to_ascii_uppercase();
+        let value = match value {
+            ConfigFileValue::Text(text) => text,
+            ConfigFileValue::Integer(number) => number.to_string(),
+            ConfigFileValue::Float(number) => number.to_string(),
+            ConfigFileValue::Flag(flag) => flag.to_string(),
+            ConfigFileValue::Table(table) => {
+                flatten_config_table(format!("{}_", name), table, settings);
+                continue;
+            }
+        };
+        settings.insert(name, value);
+    }
+}
+
+// config.toml from the working directory, or else from the user config directory
+// (e.g. ~/.config/ai-code-assistant/config.toml), as setting names and values
+pub(crate) fn load_config_file(loaded_from: &mut Vec<String>, warnings: &mut Vec<String>) -> HashMap<String, String> {
+    let candidates = [Some(PathBuf::from(CONFIG_FILE)), dirs::config_dir().map(|directory| directory.join("ai-code-assistant").join(CONFIG_FILE))];
+    let Some(path) = candidates.into_iter().flatten().find(|path| path.exists()) else {
+        return HashMap::new();
+    };
+    let file = fs::read_to_string(&path).map_err(|error| error.to_string())
+        .and_then(|content| toml::from_str::<ConfigFile>(&content).map_err(|error| error.message().to_string()));
+    match file {
+        Ok(file) => {
+            loaded_from.push(path.display().to_string());
+            let settings = file.settings();
+            let mut unknown: Vec<&String> = settings.keys()
+                .filter(|name| !KNOWN_SETTINGS.iter().any(|(known, _)| known == name))
+                .filter(|name| !MODE_OVERRIDE_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+                .collect();
+            unknown.sort();
+            for name in unknown {
+                warnings.push(format!("{} sets {}, which is not a known setting.", path.display(), name));
+            }
+            settings
+        }
+        Err(error) => {
+            warnings.push(format!("{} could not be read ({}), ignoring it.", path.display(), error));
+            HashMap::new()
+        }
+    }
+}
+
+// Reads `.env.<APP_ENV>` when an environment is selected with `--env` or APP_ENV, else `.env`.
+// Only dotenv's iterators read a file without merging it into the environment, hence the allow
+#[allow(deprecated)]
+pub(crate) fn load_env_file(environment: &HashMap<String, String>, loaded_from: &mut Vec<String>, warnings: &mut Vec<String>) -> HashMap<String, String> {
+    let app_env = arg_value("--env").or_else(|| environment.get("APP_ENV").cloned());
+    let filename = env_filename(app_env.as_deref());
+    let file = match dotenv::from_filename_iter(&filename) {
+        Ok(file) => Some((filename, file)),
+        Err(_) if filename != ".env" => {
+            warnings.push(format!("{} not found, falling back to .env.", filename));
+            dotenv::from_filename_iter(".env").ok().map(|file| (".env".to_string(), file))
+        }
+        Err(_) => None,
+    };
+    let Some((filename, file)) = file else {
+        return HashMap::new();
+    };
+    loaded_from.push(filename);
+    file.filter_map(Result::ok).collect()
+}
+
+// Explains which required setting is missing and how to provide it
+pub(crate) fn missing_setting(name: &str, description: &str, config: &Config) -> AssistantError {
+    let app_env = arg_value("--env").or_else(|| config.value("APP_ENV").map(str::to_string));
+    AssistantError::Config(format!(
+        "{} is not set ({}).\nCreate a {} file in the directory you run the assistant from, or set it in your environment, for example:\n\n    \
+         API_ENDPOINT=https://your-endpoint.example.com/v1/chat/completions\n    API_KEY=your-api-key\n\nor set it in {} as `{} = \"...\"`.",
+        name, description, env_filename(app_env.as_deref()), CONFIG_FILE, name.to_ascii_lowercase(),
+    ))
+}
+
+// The settings file for `--env` or APP_ENV; a blank name means no environment was chosen
+pub(crate) fn env_filename(app_env: Option<&str>) -> String {
+    match app_env.map(str::trim) {
+        Some(app_env) if !app_env.is_empty() => format!(".env.{}", app_env),
+        _ => ".env".to_string(),
+    }
+}
+
+pub(crate) fn resolve_setting(
+    name: &str,
+    default: Option<&str>,
+    environment: &HashMap<String, String>,
+    env_file: &HashMap<String, String>,
+    config_file: &HashMap<String, String>,
+) -> Option<SettingValue> {
+    let (value, source) = if let Some(value) = environment.get(name) {
+        (value.clone(), SettingSource::Environment)
+    } else if let Some(value) = env_file.get(name) {
+        (value.clone(), SettingSource::EnvFile)
+    } else if let Some(value) = config_file.get(name) {
+        (value.clone(), SettingSource::ConfigFile)
+    } else {
+        (default?.to_string(), SettingSource::Default)
+    };
+    Some(SettingValue { name: name.to_string(), value, source })
+}
@@ -0,0 +1,3870 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use thiserror::Error;
+
+// Quiet mode (`--quiet` or QUIET=true) sends menus, banners and notices to stderr,
+// so stdout carries nothing but the responses themselves
+static QUIET: AtomicBool = AtomicBool::new(false);
+// Verbose mode (`--verbose` or VERBOSE=true) logs every request and response to stderr
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+// Set by the first Ctrl-C; the running action notices it at its next prompt or request and backs out
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static LAST_INTERRUPT_MILLIS: AtomicU64 = AtomicU64::new(0);
+// NO_COLOR (https://no-color.org) turns colors off everywhere; set once the configuration is loaded
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+// Warnings and notices are the CLI's: `run` turns them on, while a program using the library prints nothing
+static NOTICES: AtomicBool = AtomicBool::new(false);
+const DOUBLE_INTERRUPT_MILLIS: u64 = 2000;
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_CONTEXT_LIMIT: usize = 8000; // Tokens; a conservative size that most models exceed
+const PART_NOTE_CHARS: usize = 200; // Room for the "part X of Y" note added to each part's prompt
+const MIN_PART_CHARS: usize = 1000;
+const DEFAULT_MAX_INPUT_BYTES: u64 = 1024 * 1024; // Far beyond any context window, but nowhere near a memory problem
+// Exit codes for `--mode`, so scripts can tell what happened; fatal startup errors exit with 1
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_NO_ANSWER: i32 = 3; // The mode gave up without a response, e.g. no code or a declined language check
+const EXIT_TIMED_OUT: i32 = 124;
+const EXIT_CANCELLED: i32 = 130;
+
+// Prints a line of interface chrome: stdout normally, stderr in quiet mode
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Leveled messages. `info!` is a status line, such as a cache hit, printed like `say!`; `warn!` and `error!` go to
+// stderr behind a "Warning:" or "Error:" label, so they stand out and never end up in piped output. Each is
+// colored only when its stream is a terminal. The first two print nothing until `run` turns notices on
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if NOTICES.load(Ordering::Relaxed) {
+            say!("{}", paint(&format!($($arg)*), "36", chrome_is_terminal()));
+        }
+    };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if NOTICES.load(Ordering::Relaxed) {
+            eprintln!("{} {}", paint("Warning:", "1;33", io::stderr().is_terminal()), format!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        eprintln!("{} {}", paint("Error:", "1;31", io::stderr().is_terminal()), format!($($arg)*))
+    };
+}
+
+// Prints a debugging line to stderr in verbose mode; anything that may contain the API key goes through `redact`
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!("[verbose] {}", format!($($arg)*));
+        }
+    };
+}
+
+// Prints an input prompt without a newline and flushes it so it shows before reading
+macro_rules! ask {
+    ($($arg:tt)*) => {
+        if QUIET.load(Ordering::Relaxed) {
+            eprint!($($arg)*);
+            io::stderr().flush().unwrap();
+        } else {
+            print!($($arg)*);
+            io::stdout().flush().unwrap();
+        }
+    };
+}
+
+// Declared after the macros above, which they use
+mod cache;
+mod client;
+mod config;
+mod modes;
+
+use cache::*;
+use client::*;
+pub use config::Config;
+use config::*;
+use modes::*;
+
+const MAX_REGENERATIONS: u32 = 1;
+const CONTINUE_PROMPT: &str = "Continue exactly where you left off, without repeating anything.";
+// A continuation that starts with at least this much of the end of the text before it repeated it
+const MIN_REPEATED_CHARS: usize = 8;
+const MAX_REPEATED_CHARS: usize = 1000;
+const DEFAULT_LANGUAGES: [&str; 7] = ["Python", "Rust", "JavaScript", "C++", "Java", "TypeScript", "HTML"];
+const HELP_TEXT: &str = "\
+How to use the AI Code Assistant
+
+Code modes (each asks for code, then sends one request):
+  Code Completion: completes the code and offers to append it to code_output.txt
+  Code Completion with Context: completes a region of a file, sending the whole file along as context
+  Code Explanation: explains what the code does; you can focus on a line range
+  Refactoring Suggestions: suggests improvements; you can focus on a line range
+  Explain Compiler Warnings: explains pasted warnings, optionally with the code behind them
+  Generate Property-Based Tests: finds invariants and writes tests with the language's usual library
+  Refactoring Plan: splits a refactoring into small, separately committable steps
+  Find Dead Code: points out likely unused or unreachable code
+  Describe Control Flow: walks through calls and branches, as steps or a Mermaid diagram
+  Teach Me This Pattern: explains the pattern in layers, with follow-up questions
+  Convert Sync/Async Code: rewrites code between synchronous and asynchronous styles
+  Accessibility & i18n Review: reviews HTML, JavaScript or TypeScript UI code
+  Suggest Logging & Metrics: shows where structured logs or metrics belong
+  Migrate to a New Library Version: updates code across a dependency version change
+  Refactor with Diff: shows the refactored code as a diff against yours
+  Bug & Security Review: audits the code for bugs, edge cases and security issues
+  Generate Docs: returns the code with documentation comments in the language's own style
+  Compare Two Snippets: weighs two implementations of the same thing against each other
+  Fix Compiler Error: fixes the code given the error it produces, and explains the fix
+  Draw a Diagram: draws the call graph or class relationships as Mermaid or PlantUML, to save as a file
+  Explain a Diff: summarizes what a unified diff (e.g. from git diff) changes and flags its risks
+  Translate Code: ports the code idiomatically into another language
+  Continue Last Response: asks for more of the latest response, from this session or the history
+
+Change Language switches the language for the following requests, keeping the conversation.
+
+Giving code: type or paste it and finish with a line containing only END, or read it
+from a file (code_input.txt by default). `--file <path>` skips the question. Code read
+from a file is checked against the chosen language by its extension.
+
+Before a request is sent you can review the prompt, append instructions or edit it in
+$EDITOR. Press Ctrl-C to cancel the current action. Prompts that look like they contain
+passwords, keys or tokens are flagged first, with an offer to redact them (SECRET_SCAN).
+
+Cache: answers are saved in ~/.cache/ai-code-assistant/cache.jsonl (or CACHE_PATH) and
+reused when the same request is made again, so repeats cost nothing; --no-cache asks for a
+fresh answer and leaves the cache alone. Use View Cache,
+Show Cached Response, Remove Cache Entry and Clear Cache to browse and manage it. Every answered request is also kept in history.jsonl; Search History finds
+earlier ones.
+
+Conversation: follow-up requests include the earlier ones as context until you choose
+Clear Conversation.
+
+Repeating: after a code mode, press Enter or r at the menu to run it again with the same
+language; only the new code is asked for.
+
+Settings: View Settings shows every setting and where it came from. Settings are read
+from the environment or a .env file; see the README for the full list.";
+
+// How much each language scales the modes' default token limits
+const LANGUAGE_TOKEN_FACTORS: &[(&str, f64)] = &[
+    ("C++", 1.5), ("Java", 1.4), ("C", 1.3), ("C#", 1.3), ("Rust", 1.2), ("Go", 1.1), ("TypeScript", 1.1),
+    ("JavaScript", 1.0), ("Python", 0.8), ("Ruby", 0.8), ("SQL", 0.7),
+];
+// Below this share of the detector's points, a snippet isn't trusted to be in the specified language
+const LANGUAGE_CONFIDENCE_THRESHOLD: f64 = 0.4;
+// Weighted evidence for every language the detector recognizes: keywords, comment syntax, common library calls and file extensions
+const LANGUAGE_SIGNALS: &[(&str, &[(&str, u32)])] = &[
+    ("Rust", &[
+        ("fn ", 3), ("let mut ", 4), ("let ", 1), ("println!", 5), ("impl ", 4), ("pub fn ", 4), ("use std::", 6),
+        ("&mut ", 3), ("&str", 4), ("Vec<", 3), ("Option<", 3), ("Result<", 3), ("#[derive", 6), ("unwrap()", 3),
+        ("match ", 1), ("-> ", 1), ("///", 2), (".rs", 2),
+    ]),
+    ("Python", &[
+        ("def ", 3), ("elif ", 5), ("self.", 2), ("__init__", 5), ("__name__", 5), ("print(", 2), ("import ", 1),
+        ("lambda ", 3), ("None", 2), ("True", 1), ("False", 1), ("\"\"\"", 3), ("# ", 1), ("):\n", 2), (".py", 2),
+    ]),
+    ("JavaScript", &[
+        ("function ", 3), ("console.log", 6), ("const ", 2), ("let ", 1), ("=> ", 2), ("===", 4), ("require(", 4),
+        ("document.", 5), ("window.", 4), ("undefined", 3), ("export ", 2), (".then(", 3), ("// ", 1), (".js", 2),
+    ]),
+    // `#include` alone is shared with C, so C++ is told apart by what C doesn't have
+    ("C++", &[
+        ("#include", 3), ("std::", 6), ("cout", 6), ("cin >>", 5), ("int main(", 2), ("nullptr", 6), ("template<", 6),
+        ("template <", 6), ("namespace ", 4), ("using namespace std", 8), ("vector<", 3), ("#include <iostream>", 8),
+        ("public:", 5), ("private:", 5), ("};", 2), ("// ", 1), (".cpp", 2), (".hpp", 2),
+    ]),
+    ("C", &[
+        ("#include", 3), ("#include <stdio.h>", 8), ("#include <stdlib.h>", 8), ("printf(", 4), ("scanf(", 5),
+        ("malloc(", 5), ("free(", 3), ("sizeof(", 2), ("typedef struct", 6), ("struct ", 1), ("int main(", 2),
+        ("NULL", 2), ("/* ", 1), (".c\"", 2), (".h\"", 2),
+    ]),
+    ("Java", &[
+        ("public static void main", 8), ("System.out.", 8), ("System.err.", 6), ("import java.", 8), ("import javax.", 8),
+        ("package ", 3), ("public class ", 5), ("@Override", 6), ("String[]", 4), ("implements ", 3), ("extends ", 2),
+        ("private ", 2), ("public ", 2), ("final ", 2), ("void ", 2), ("new ", 1), ("// ", 1), (".java", 2),
+    ]),
+    ("Go", &[
+        ("package main", 6), ("func ", 3), (" := ", 3), ("fmt.Println", 6), ("fmt.Printf", 6), ("import (", 5),
+        ("err != nil", 6), ("go func", 5), ("chan ", 3), ("defer ", 4), ("// ", 1), (".go", 2),
+    ]),
+    ("TypeScript", &[
+        ("interface ", 3), (": string", 4), (": number", 4), (": boolean", 4), ("as const", 5), ("readonly ", 3),
+        ("type ", 1), ("const ", 1), ("=> ", 1), ("export ", 1), ("console.log", 2), ("// ", 1), (".ts", 2),
+    ]),
+    // Markup outweighs the JavaScript of inline scripts and handlers
+    ("HTML", &[
+        ("<!DOCTYPE html", 10), ("<html", 8), ("<head>", 6), ("<body", 6), ("</div>", 4), ("<div", 3), ("<p>", 3),
+        ("<a href=", 4), ("<img ", 4), ("alt=\"", 4), ("class=\"", 2), ("<script", 2), ("<!-- ", 2), (".html", 2),
+    ]),
+    ("SQL", &[
+        ("SELECT ", 4), ("FROM ", 3), ("WHERE ", 3), ("INSERT INTO", 6), ("CREATE TABLE", 6), ("JOIN ", 3),
+        ("GROUP BY", 5), ("ORDER BY", 4), ("select ", 2), ("from ", 1), ("where ", 1), ("-- ", 1), (".sql", 2),
+    ]),
+    ("C#", &[
+        ("using System", 8), ("Console.WriteLine", 8), ("static void Main", 6), ("{ get; set; }", 8), ("namespace ", 2),
+        ("public class ", 2), ("var ", 1), ("async Task", 5), ("// ", 1), (".cs", 2),
+    ]),
+    ("Ruby", &[
+        ("puts ", 4), ("elsif ", 5), ("attr_accessor", 8), ("do |", 6), ("require '", 4), ("def ", 2), ("end\n", 2),
+        ("nil", 2), ("# ", 1), (".rb", 2),
+    ]),
+];
+const CLI_MODES: [&str; 25] = [
+    "complete", "explain", "refactor", "help", "warnings", "proptest", "refactor-plan",
+    "dead-code", "flow", "teach", "async", "a11y", "logging", "migrate", "refactor-diff", "review", "ask",
+    "translate", "continue", "complete-context", "explain-diff", "docs", "compare", "fix-error",
+    "diagram",
+];
+// Every command-line flag, for the completion scripts
+const CLI_FLAGS: [(&str, FlagValue, &str); 27] = [
+    ("--mode", FlagValue::Mode, "Run one action and exit"),
+    ("--lang", FlagValue::Language, "The language of the code"),
+    ("--to", FlagValue::Language, "The language to translate into"),
+    ("--file", FlagValue::Path, "Read the code from this file"),
+    ("--context", FlagValue::Path, "The whole file the code to complete belongs to"),
+    ("--against", FlagValue::Path, "The second version to compare the code with"),
+    ("--error", FlagValue::Path, "Read the error to fix from this file"),
+    ("--batch", FlagValue::Path, "Run the mode over every file matching a glob"),
+    ("--output", FlagValue::Path, "Save the response to this file"),
+    ("--out", FlagValue::Text, "Where --batch writes each response, e.g. reviews/{stem}.{mode}.md"),
+    ("--lines", FlagValue::Text, "Focus explanations and refactorings on a line range, e.g. 10-25"),
+    ("--env", FlagValue::Text, "Load .env.<name> instead of .env"),
+    ("--max-lines", FlagValue::Text, "Stop a streamed response after this many lines"),
+    ("--max-chars", FlagValue::Text, "Stop a streamed response after this many characters"),
+    ("--code-only", FlagValue::None, "Keep only the code blocks of the response"),
+    ("--clipboard", FlagValue::None, "Copy each response to the clipboard"),
+    ("--json", FlagValue::None, "Print the response as a JSON object"),
+    ("--stream", FlagValue::None, "Print the response as it is generated"),
+    ("--quiet", FlagValue::None, "Send prompts and notices to stderr"),
+    ("--verbose", FlagValue::None, "Log requests and responses to stderr"),
+    ("--dry-run", FlagValue::None, "Print request payloads instead of sending them"),
+    ("--golden", FlagValue::Path, "Check the prompts a mode builds against this file instead of sending them"),
+    ("--no-cache", FlagValue::None, "Always send requests and leave the cache file alone"),
+    ("--review-passes", FlagValue::Text, "Have refactorings critiqued and improved this many times"),
+    ("--mermaid", FlagValue::None, "Describe the control flow as a Mermaid diagram"),
+    ("--yes", FlagValue::None, "Answer yes to confirmations"),
+    ("--force", FlagValue::None, "Answer yes to confirmations and overwrite --out files"),
+];
+const COMPLETION_SHELLS: [&str; 4] = ["bash", "zsh", "fish", "powershell"];
+
+// What a flag's completion offers after it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FlagValue {
+    None,
+    Mode,
+    Language,
+    Path,
+    Text,
+}
+const CODE_INPUT_FILE: &str = "code_input.txt";
+const CODE_OUTPUT_FILE: &str = "code_output.txt";
+const HISTORY_FILE: &str = "history.jsonl";
+const PROMPTS_FILE: &str = "prompts.json";
+
+/// One chat message as sent to the API: `role` is `system`, `user` or `assistant`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub role: String,
+    pub content: String,
+}
+
+// One answered request in the history file, which keeps everything and is never trimmed, unlike the cache
+#[derive(Serialize, Deserialize, Debug)]
+struct HistoryEntry {
+    timestamp: u64, // UNIX timestamp in seconds
+    mode: String,
+    language: String,
+    prompt: String,
+    response: String,
+    #[serde(default)]
+    usage: Option<Usage>,
+    #[serde(default)]
+    elapsed_ms: Option<u64>,
+}
+
+// State that lives for one run of the assistant: the persistent cache and the running conversation
+struct Session {
+    cache: Arc<Mutex<Cache>>, // Shared with the workers of a parallel batch
+    in_flight: Arc<InFlight>, // Requests being sent right now, shared like the cache
+    conversation: Conversation,
+    code_file: Option<String>, // Set by `--file`; otherwise each mode asks where the code comes from
+    focus_lines: Option<String>, // Set by `--lines`; otherwise explanations and refactorings ask for a range at a terminal
+    output_file: Option<String>, // Set by `--output`; each response is also written there
+    clipboard: Option<ClipboardCopy>, // Set by `--clipboard` or CLIPBOARD; each response is also copied there
+    code_only: bool, // Set by `--code-only`; completions print only their fenced code
+    highlighter: Option<Highlighter>, // None when colors are off
+    cost_preview: CostPreview,
+    stream: bool, // Print responses token by token as they arrive
+    tokens_used: Cell<u64>, // Reported usage of every request so far
+    latencies: RefCell<Vec<Duration>>, // How long each request sent took, for the batch summary
+    cache_hits: u32,
+    answered: u32, // Requests that produced a response, from the cache or the API
+    prompts: PromptTemplates,
+    json: Option<JsonOutput>, // Set by `--json`; each response is printed as one JSON object instead of prose
+    review_prompt: bool, // Show each prompt and let the user add to or edit it before it is sent
+    interactive: bool, // Stdin is a terminal that can be asked questions; never for batch workers
+    assume_yes: bool, // Set by `--yes` or `--force`; confirmations are answered yes without asking
+    dry_run: bool, // Set by `--dry-run`; print each request payload instead of sending it
+    golden: Option<Golden>, // Set by `--golden`; record each request's messages instead of sending it
+    review_passes: u32, // Set by `--review-passes` or REVIEW_PASSES; how often a refactoring is critiqued and improved
+    cache_enabled: bool, // Off with `--no-cache` or CACHE_ENABLED=false: every request is sent and nothing is cached or saved
+    last_action: Option<String>, // The menu option that last sent a request, which Enter or `r` runs again
+    history_file: Option<String>, // HISTORY_FILE, where every answer is appended; None keeps no history
+    config: Config, // Where the settings read while the session runs come from
+}
+
+impl Session {
+    // A worker that panicked mid-update leaves the cache usable, so a poisoned lock is still taken
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // A session for one batch worker: the same cache and settings, with its own conversation and counters.
+    // Workers print whole responses only, since streamed or highlighted output would interleave
+    fn worker(&self) -> Session {
+        Session {
+            cache: Arc::clone(&self.cache),
+            in_flight: Arc::clone(&self.in_flight),
+            conversation: Conversation::new(self.conversation.limit),
+            code_file: None,
+            focus_lines: self.focus_lines.clone(),
+            output_file: None,
+            clipboard: None,
+            code_only: self.code_only,
+            highlighter: None,
+            cost_preview: self.cost_preview.clone(),
+            stream: false,
+            tokens_used: Cell::new(0),
+            latencies: RefCell::new(Vec::new()),
+            cache_hits: 0,
+            answered: 0,
+            prompts: self.prompts.clone(),
+            json: self.json.clone(),
+            review_prompt: false,
+            interactive: false,
+            assume_yes: self.assume_yes,
+            dry_run: self.dry_run,
+            golden: None,
+            review_passes: self.review_passes,
+            cache_enabled: self.cache_enabled,
+            last_action: None,
+            history_file: self.history_file.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+// The messages of every request a `--golden` run would have sent, compared with the file once the mode ends
+struct Golden {
+    path: String,
+    replace: bool, // `--force`: record the prompts again instead of checking them
+    requests: RefCell<Vec<Vec<Message>>>,
+}
+
+impl Golden {
+    // A missing file, or `--force`, records the prompts as they are now; otherwise they have to match it
+    fn finish(self) -> Result<(), AssistantError> {
+        let requests = self.requests.into_inner();
+        if requests.is_empty() {
+            return Err(AssistantError::Input("The mode sent no request, so there is no prompt to check".to_string()));
+        }
+        let actual = serde_json::to_string_pretty(&requests)? + "\n";
+        let expected = match fs::read_to_string(&self.path) {
+            Ok(expected) if !self.replace => expected,
+            Ok(_) => String::new(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error.into()),
+        };
+        if expected.is_empty() {
+            if let Some(directory) = Path::new(&self.path).parent().filter(|directory| !directory.as_os_str().is_empty()) {
+                fs::create_dir_all(directory)?;
+            }
+            fs::write(&self.path, &actual)?;
+            info!("Wrote {} request(s) to {}.", requests.len(), self.path);
+            return Ok(());
+        }
+        // Line endings are normalized, so a checkout with CRLF still matches
+        let expected = expected.replace("\r\n", "\n");
+        match actual.lines().zip(expected.lines()).position(|(actual, expected)| actual != expected) {
+            None if actual.lines().count() == expected.lines().count() => {
+                info!("The prompts match {}.", self.path);
+                Ok(())
+            }
+            position => Err(AssistantError::PromptChanged {
+                path: self.path,
+                line: position.unwrap_or_else(|| actual.lines().count().min(expected.lines().count())) + 1,
+            }),
+        }
+    }
+}
+
+// What `--json` reports alongside every response
+#[derive(Clone)]
+struct JsonOutput {
+    mode: String,
+    language: String,
+}
+
+// One response as printed by `--json`
+#[derive(Serialize)]
+struct JsonReply<'a> {
+    ok: bool, // Always true; failures are printed as a JsonFailure
+    mode: &'a str,
+    language: &'a str,
+    prompt_hash: Option<&'a str>,
+    response: &'a str,
+    cached: bool,
+    finish_reason: Option<&'a str>, // None for cached responses, whose finish reason wasn't kept
+    usage: Option<Usage>,
+    elapsed_ms: Option<u64>, // None for cached responses
+}
+
+// What `--json` prints instead of a response when the mode fails, so every outcome is one JSON object.
+// The mode and language are None when the failure came before they were known
+#[derive(Serialize)]
+struct JsonFailure<'a> {
+    ok: bool, // Always false
+    mode: Option<&'a str>,
+    language: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a str>, // The file a batch failed on
+    error: JsonError,
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    kind: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>, // The HTTP status, for `auth` and `http` errors
+}
+
+// Colors the fenced code in responses, using the fence's language tag or else the session's language
+struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+    language: String,
+}
+
+impl Highlighter {
+    fn new(language: &str) -> Highlighter {
+        let mut themes = ThemeSet::load_defaults();
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme: themes.themes.remove("base16-ocean.dark").unwrap_or_default(),
+            language: language.to_string(),
+        }
+    }
+
+    // Lines inside fences get ANSI colors; prose has its markdown rendered as terminal styles
+    fn highlight(&self, text: &str) -> String {
+        let mut output = String::new();
+        let mut block: Option<HighlightLines> = None;
+        for line in LinesWithEndings::from(text) {
+            let fence = line.trim_start().strip_prefix("```");
+            if let Some(tag) = fence {
+                block = match block {
+                    Some(_) => None,
+                    None => Some(HighlightLines::new(self.syntax_for(tag.trim()), &self.theme)),
+                };
+                output.push_str(line);
+                continue;
+            }
+            match block.as_mut().map(|highlighter| highlighter.highlight_line(line, &self.syntaxes)) {
+                Some(Ok(ranges)) => {
+                    // Reset before the line break so the colors never bleed into the prose that follows
+                    let escaped = as_24_bit_terminal_escaped(&ranges, false);
+                    let (code, newline) = escaped.split_at(escaped.trim_end_matches('\n').len());
+                    output.push_str(&format!("{}\x1b[0m{}", code, newline));
+                }
+                Some(Err(_)) => output.push_str(line),
+                None => {
+                    let (prose, newline) = line.split_at(line.trim_end_matches(['\r', '\n']).len());
+                    output.push_str(&render_markdown_line(prose));
+                    output.push_str(newline);
+                }
+            }
+        }
+        output
+    }
+
+    fn syntax_for(&self, tag: &str) -> &SyntaxReference {
+        let token = if tag.is_empty() { self.language.as_str() } else { tag };
+        self.syntaxes.find_syntax_by_token(token)
+            .or_else(|| self.syntaxes.find_syntax_by_name(&self.language))
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text())
+    }
+}
+
+// Headers become bold and underlined, list markers bullets, and emphasis and inline code styled,
+// with the markdown characters themselves removed
+fn render_markdown_line(line: &str) -> String {
+    let body = line.trim_start();
+    let indent = &line[..line.len() - body.len()];
+    let hashes = body.chars().take_while(|&character| character == '#').count();
+    if (1..=6).contains(&hashes) && body[hashes..].starts_with(' ') {
+        return format!("{}\x1b[1;4m{}\x1b[0m", indent, render_inline_markdown(body[hashes..].trim()));
+    }
+    match ["- ", "* ", "+ "].iter().find(|marker| body.starts_with(**marker)) {
+        Some(marker) => format!("{}• {}", indent, render_inline_markdown(&body[marker.len()..])),
+        None => format!("{}{}", indent, render_inline_markdown(body)),
+    }
+}
+
+// `**bold**`, `*italic*` and `` `code` ``. A marker followed by a space, as in `2 * 3`, or one that is
+// never closed is left as it is; underscores are too, since they are common in identifiers
+fn render_inline_markdown(text: &str) -> String {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['*', '`']) {
+        output.push_str(&rest[..start]);
+        let tail = &rest[start..];
+        let (marker, style, unstyle) = if tail.starts_with("**") {
+            ("**", "\x1b[1m", "\x1b[22m")
+        } else if tail.starts_with('`') {
+            ("`", "\x1b[36m", "\x1b[39m")
+        } else {
+            ("*", "\x1b[3m", "\x1b[23m")
+        };
+        let after = &tail[marker.len()..];
+        match after.find(marker) {
+            Some(end) if end > 0 && !after.starts_with(' ') => {
+                let inner = &after[..end];
+                let inner = if marker == "`" { inner.to_string() } else { render_inline_markdown(inner) };
+                output.push_str(&format!("{}{}{}", style, inner, unstyle));
+                rest = &after[end + marker.len()..];
+            }
+            _ => {
+                output.push_str(marker);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+fn use_color() -> bool {
+    io::stdout().is_terminal() && colors_allowed()
+}
+
+// Colors are for terminals only, and not at all with NO_COLOR
+fn colors_allowed() -> bool {
+    !NO_COLOR.load(Ordering::Relaxed)
+}
+
+// Wraps `text` in the SGR color `code` (e.g. "1;31" for bold red) when it is going to a terminal
+fn paint(text: &str, code: &str, terminal: bool) -> String {
+    if terminal && colors_allowed() {
+        format!("\x1b[{}m{}\x1b[0m", code, text)
+    } else {
+        text.to_string()
+    }
+}
+
+// Whether `say!` output, stdout or stderr in quiet mode, is going to a terminal
+fn chrome_is_terminal() -> bool {
+    if QUIET.load(Ordering::Relaxed) {
+        io::stderr().is_terminal()
+    } else {
+        io::stdout().is_terminal()
+    }
+}
+
+// Earlier prompts and replies, resent with each request so follow-up questions keep their context
+struct Conversation {
+    messages: Vec<Message>,
+    limit: usize, // Oldest messages are dropped beyond this many, to stay inside the model's context window
+}
+
+impl Conversation {
+    fn new(limit: usize) -> Conversation {
+        Conversation { messages: Vec::new(), limit }
+    }
+
+    fn record(&mut self, prompt: String, reply: String) {
+        self.messages.push(Message { role: "user".to_string(), content: prompt });
+        self.messages.push(Message { role: "assistant".to_string(), content: reply });
+        let excess = self.messages.len().saturating_sub(self.limit);
+        self.messages.drain(..excess);
+        // Never start the history on a dangling reply
+        if self.messages.first().is_some_and(|message| message.role == "assistant") {
+            self.messages.remove(0);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    // The latest exchange, as (prompt, reply)
+    fn last_exchange(&self) -> Option<(&str, &str)> {
+        match self.messages.as_slice() {
+            [.., prompt, reply] if reply.role == "assistant" => Some((&prompt.content, &reply.content)),
+            _ => None,
+        }
+    }
+
+    // Replaces the latest reply, e.g. with itself plus its continuation
+    fn replace_last_reply(&mut self, text: String) {
+        if let Some(reply) = self.messages.last_mut().filter(|message| message.role == "assistant") {
+            reply.content = text;
+        }
+    }
+
+    // Fingerprint of the history, so a cached answer is only reused for the same conversation
+    fn fingerprint(&self) -> u64 {
+        let transcript: String = self.messages.iter().map(|message| format!("{}\n{}\n", message.role, message.content)).collect();
+        fnv1a_hash(&transcript)
+    }
+}
+
+// The language a snippet most likely is, with the share of detection points it scored (0.0 to 1.0)
+struct DetectedLanguage {
+    name: String,
+    confidence: f64,
+}
+
+// The name the assistant was started as, which the completion scripts register under
+fn program_name() -> String {
+    env::args().next()
+        .and_then(|path| Path::new(&path).file_stem().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "final_project".to_string())
+}
+
+// A completion script for `eval "$(final_project completions zsh)"`, built from the flag and mode tables
+fn completion_script(shell: &str, program: &str, config: &Config) -> Result<String, AssistantError> {
+    let modes = CLI_MODES.join(" ");
+    let languages = supported_languages(config).join(" ");
+    let flags = |kinds: &[FlagValue]| CLI_FLAGS.iter().filter(|(_, kind, _)| kinds.contains(kind)).map(|(flag, _, _)| *flag).collect::<Vec<_>>();
+    let function = format!("_{}", program.replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+    let script = match shell {
+        "bash" => format!(
+            "{function}() {{
+    local cur=\"${{COMP_WORDS[COMP_CWORD]}}\" prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+    case \"$prev\" in
+        --mode) COMPREPLY=($(compgen -W \"{modes}\" -- \"$cur\")); return ;;
+        {language_flags}) COMPREPLY=($(compgen -W \"{languages}\" -- \"$cur\")); return ;;
+        {path_flags}) COMPREPLY=($(compgen -f -- \"$cur\")); return ;;
+        {text_flags}) return ;;
+    esac
+    COMPREPLY=($(compgen -W \"{all_flags}\" -- \"$cur\"))
+}}
+complete -o filenames -F {function} {program}
+",
+            language_flags = flags(&[FlagValue::Language]).join("|"),
+            path_flags = flags(&[FlagValue::Path]).join("|"),
+            text_flags = flags(&[FlagValue::Text]).join("|"),
+            all_flags = CLI_FLAGS.map(|(flag, _, _)| flag).join(" "),
+        ),
+        "zsh" => {
+            let specs: Vec<String> = CLI_FLAGS.iter().map(|(flag, kind, description)| match kind {
+                FlagValue::None => format!("    '{}[{}]'", flag, description),
+                FlagValue::Mode => format!("    '{}=[{}]:mode:({})'", flag, description, modes),
+                FlagValue::Language => format!("    '{}=[{}]:language:({})'", flag, description, languages),
+                FlagValue::Path => format!("    '{}=[{}]:file:_files'", flag, description),
+                FlagValue::Text => format!("    '{}=[{}]:value: '", flag, description),
+            }).collect();
+            format!("#compdef {program}\n{function}() {{\n  _arguments \\\n{}\n}}\ncompdef {function} {program}\n", specs.join(" \\\n"))
+        }
+        "fish" => {
+            let mut lines = vec![format!("complete -c {} -f", program)];
+            lines.extend(CLI_FLAGS.iter().map(|(flag, kind, description)| {
+                let name = flag.trim_start_matches('-');
+                match kind {
+                    FlagValue::None => format!("complete -c {} -l {} -d '{}'", program, name, description),
+                    FlagValue::Mode => format!("complete -c {} -l {} -x -a '{}' -d '{}'", program, name, modes, description),
+                    FlagValue::Language => format!("complete -c {} -l {} -x -a '{}' -d '{}'", program, name, languages, description),
+                    FlagValue::Path => format!("complete -c {} -l {} -r -F -d '{}'", program, name, description),
+                    FlagValue::Text => format!("complete -c {} -l {} -x -d '{}'", program, name, description),
+                }
+            }));
+            lines.join("\n") + "\n"
+        }
+        "powershell" => {
+            let quoted = |words: &str| words.split(' ').map(|word| format!("'{}'", word)).collect::<Vec<_>>().join(", ");
+            let cases = |kinds: &[FlagValue]| flags(kinds).iter().map(|flag| format!("'{}'", flag)).collect::<Vec<_>>().join(", ");
+            format!(
+                "Register-ArgumentCompleter -Native -CommandName '{program}' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})
+    $previous = if ($wordToComplete) {{ $words[-2] }} else {{ $words[-1] }}
+    if (@({file_flags}) -contains $previous) {{ return }}
+    $values = if ($previous -eq '--mode') {{ @({modes}) }}
+        elseif (@({language_flags}) -contains $previous) {{ @({languages}) }}
+        elseif (@({text_flags}) -contains $previous) {{ @() }}
+        else {{ @({all_flags}) }}
+    $values | Where-Object {{ $_ -like \"$wordToComplete*\" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+",
+                file_flags = cases(&[FlagValue::Path]),
+                modes = quoted(&modes),
+                language_flags = cases(&[FlagValue::Language]),
+                languages = quoted(&languages),
+                text_flags = cases(&[FlagValue::Text]),
+                all_flags = CLI_FLAGS.map(|(flag, _, _)| format!("'{}'", flag)).join(", "),
+            )
+        }
+        _ => return Err(AssistantError::Config(format!("Usage: {} completions <{}>", program, COMPLETION_SHELLS.join("|")))),
+    };
+    Ok(script)
+}
+
+// The value following a flag on the command line, accepting both `--flag value` and `--flag=value`
+fn arg_value(flag: &str) -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(flag).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+// Requests estimated above `threshold` tokens ask for confirmation, quoting a price when one is configured
+#[derive(Clone)]
+struct CostPreview {
+    threshold: usize,
+    price_per_1k: Option<f64>,
+}
+
+/// The assistant's core for use from other programs: one endpoint, one language and a cache shared by
+/// every request. Nothing is asked on stdin or printed, and nothing is read from the environment or the
+/// working directory: settings come from the `Config` it is built with. With the `async` feature, each
+/// method also has an `_async` twin.
+pub struct Assistant {
+    client: Box<dyn ApiClient + Send>, // Send, so an assistant can be moved into a spawned task
+    #[cfg(feature = "async")]
+    async_client: nonblocking::AsyncHttpClient, // Shares the retry budget and rate limit with `client`
+    language: String,
+    cache: Mutex<Cache>, // Locked only around lookups and inserts, so async requests can run side by side
+    #[cfg(feature = "async")]
+    in_flight: Arc<InFlight>, // So identical async requests running together are sent only once
+    cache_file: Option<String>, // Where `save_cache` writes; None keeps the cache in memory only
+    prompts: PromptTemplates,
+    model: Option<String>,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    review_passes: u32,
+    config: Config,
+}
+
+impl Assistant {
+    /// An assistant calling a chat completions `endpoint`, sending `key` as an `api-key` header, with every
+    /// setting at its default.
+    pub fn new(endpoint: &str, key: Option<&str>, language: &str) -> Result<Assistant, AssistantError> {
+        Assistant::from_config(endpoint, key, language, Config::default())
+    }
+
+    /// Like `new`, with the settings in `config`: AUTH_STYLE, the sampling and cache settings, or the prompt
+    /// templates in PROMPTS_FILE. `Config::load()` gives the ones the CLI would use.
+    pub fn from_config(endpoint: &str, key: Option<&str>, language: &str, config: Config) -> Result<Assistant, AssistantError> {
+        let prompts = match config.value("PROMPTS_FILE") {
+            Some(filename) => PromptTemplates::load(filename)?,
+            None => PromptTemplates::defaults(),
+        };
+        let language = canonical_language(&config, language)
+            .ok_or_else(|| AssistantError::Config(format!("Unsupported language '{}'. Use one of: {}.", language, supported_languages(&config).join(", "))))?;
+        let mut cache = Cache::default();
+        cache.configure(&config);
+        let client = Arc::new(HttpClient::new(endpoint.to_string(), key.map(str::to_string), auth_style(&config), &config));
+        Ok(Assistant {
+            #[cfg(feature = "async")]
+            async_client: nonblocking::AsyncHttpClient::new(Arc::clone(&client))?,
+            client: Box::new(client),
+            language,
+            cache: Mutex::new(cache),
+            #[cfg(feature = "async")]
+            in_flight: Arc::new(InFlight::default()),
+            cache_file: None,
+            prompts,
+            model: None,
+            temperature: None,
+            max_tokens: None,
+            review_passes: 0,
+            config,
+        })
+    }
+
+    /// Loads the cache from `path`, which may not exist yet, and saves it there on `save_cache`.
+    pub fn with_cache_file(self, path: &str) -> Result<Assistant, AssistantError> {
+        let mut cache = load_cache(path)?;
+        cache.configure(&self.config);
+        Ok(Assistant { cache: Mutex::new(cache), cache_file: Some(path.to_string()), ..self })
+    }
+
+    pub fn with_model(self, model: &str) -> Assistant {
+        Assistant { model: Some(model.to_string()), ..self }
+    }
+
+    pub fn with_temperature(self, temperature: f32) -> Assistant {
+        Assistant { temperature: Some(temperature), ..self }
+    }
+
+    pub fn with_max_tokens(self, max_tokens: u32) -> Assistant {
+        Assistant { max_tokens: Some(max_tokens), ..self }
+    }
+
+    /// Has `refactor` ask the model to critique and improve its answer `passes` times before returning it.
+    pub fn with_review_passes(self, passes: u32) -> Assistant {
+        Assistant { review_passes: passes, ..self }
+    }
+
+    /// Completes `code`; the response usually holds the completed code in a fenced block.
+    pub fn complete(&self, code: &str) -> Result<String, AssistantError> {
+        self.request(self.completion_request(code))
+    }
+
+    /// Completes `code`, a region of the file `context`, with the whole file sent along for its imports and types.
+    pub fn complete_in_context(&self, context: &str, code: &str) -> Result<String, AssistantError> {
+        self.request(self.context_completion_request(context, code))
+    }
+
+    pub fn explain(&self, code: &str) -> Result<String, AssistantError> {
+        self.request(self.explanation_request(code))
+    }
+
+    pub fn refactor(&self, code: &str) -> Result<String, AssistantError> {
+        let mut answer = self.request(self.refactoring_request(code))?;
+        for _ in 0..self.review_passes {
+            answer = self.request(self.critique_request(code, &answer))?;
+        }
+        Ok(answer)
+    }
+
+    /// Answers a free-form question about the language.
+    pub fn ask(&self, question: &str) -> Result<String, AssistantError> {
+        self.request(self.question_request(question))
+    }
+
+    /// Returns `code` with documentation comments in the language's own style, in a fenced block.
+    pub fn generate_docs(&self, code: &str) -> Result<String, AssistantError> {
+        self.request(self.docs_request(code))
+    }
+
+    /// Compares two implementations of the same thing for correctness, performance and style.
+    pub fn compare(&self, first: &str, second: &str) -> Result<String, AssistantError> {
+        self.request(self.compare_request(first, second))
+    }
+
+    /// Explains the change a unified `diff` makes and flags its risks.
+    pub fn explain_diff(&self, diff: &str) -> Result<String, AssistantError> {
+        self.request(self.diff_request(diff))
+    }
+
+    /// Fixes `code` given the compiler or runtime `error` it produces; the response holds the corrected
+    /// code in a fenced block, followed by an explanation of the fix.
+    pub fn fix_error(&self, code: &str, error: &str) -> Result<String, AssistantError> {
+        self.request(self.fix_error_request(code, error))
+    }
+
+    /// The messages a request would send, built as the methods above build them but without sending
+    /// anything, so prompts can be checked against a snapshot. `mode` names a template (`completion`,
+    /// `contextcompletion`, `explanation`, `refactoring`, `question`, `docs`, `compare`, `diff`,
+    /// `fixerror` or `diagram`, for the call graph) and `inputs` are the arguments its method takes, in order.
+    pub fn messages(&self, mode: &str, inputs: &[&str]) -> Result<Vec<Message>, AssistantError> {
+        let request = match (mode, inputs) {
+            ("completion", [code]) => self.completion_request(code),
+            ("contextcompletion", [context, code]) => self.context_completion_request(context, code),
+            ("explanation", [code]) => self.explanation_request(code),
+            ("refactoring", [code]) => self.refactoring_request(code),
+            ("question", [question]) => self.question_request(question),
+            ("docs", [code]) => self.docs_request(code),
+            ("compare", [first, second]) => self.compare_request(first, second),
+            ("diff", [diff]) => self.diff_request(diff),
+            ("fixerror", [code, error]) => self.fix_error_request(code, error),
+            ("diagram", [code]) => self.diagram_request(code, false)?,
+            _ => return Err(AssistantError::Input(format!("No '{}' request takes {} input(s)", mode, inputs.len()))),
+        };
+        Ok(build_messages(&request.system_prompt.text, &[], &request.prompt))
+    }
+
+    /// Draws the call graph of `code`, or with `class_relationships` how its types relate, as a single fenced
+    /// block in the format set by DIAGRAM_FORMAT (`mermaid` or `plantuml`).
+    pub fn diagram(&self, code: &str, class_relationships: bool) -> Result<String, AssistantError> {
+        self.request(self.diagram_request(code, class_relationships)?)
+    }
+
+    /// Writes the cache to the file given to `with_cache_file`, if any.
+    pub fn save_cache(&self) -> Result<(), AssistantError> {
+        match &self.cache_file {
+            Some(path) => save_cache(path, &mut self.cache()),
+            None => Ok(()),
+        }
+    }
+
+    fn completion_request(&self, code: &str) -> ModeRequest {
+        let prompt = self.prompts.render("completion", &[("language", &self.language), ("code", code)]);
+        ModeRequest::new(&self.config, "completion", &self.language, prompt, 500)
+    }
+
+    fn context_completion_request(&self, context: &str, code: &str) -> ModeRequest {
+        let prompt = self.prompts.render("contextcompletion", &[("language", &self.language), ("context", context), ("code", code)]);
+        ModeRequest::prefixed(&self.config, "contextcompletion", &self.language, prompt, 500).keyed_on(context)
+    }
+
+    fn explanation_request(&self, code: &str) -> ModeRequest {
+        let prompt = self.prompts.render("explanation", &[("language", &self.language), ("code", code)]);
+        ModeRequest::new(&self.config, "explanation", &self.language, prompt, 500)
+    }
+
+    fn refactoring_request(&self, code: &str) -> ModeRequest {
+        let prompt = self.prompts.render("refactoring", &[("language", &self.language), ("code", code)]);
+        ModeRequest::new(&self.config, "refactoring", &self.language, prompt, 500)
+    }
+
+    fn critique_request(&self, code: &str, draft: &str) -> ModeRequest {
+        let prompt = self.prompts.render("critique", &[("language", &self.language), ("code", code), ("draft", draft)]);
+        ModeRequest::prefixed(&self.config, "critique", &self.language, prompt, 700)
+    }
+
+    fn question_request(&self, question: &str) -> ModeRequest {
+        let prompt = self.prompts.render("question", &[("language", &self.language), ("question", question)]);
+        ModeRequest::prefixed(&self.config, "question", &self.language, prompt, 600)
+    }
+
+    fn docs_request(&self, code: &str) -> ModeRequest {
+        let prompt = self.prompts.render("docs", &[("language", &self.language), ("style", doc_comment_style(&self.language)), ("code", code)]);
+        ModeRequest::prefixed(&self.config, "docs", &self.language, prompt, 1000)
+    }
+
+    fn compare_request(&self, first: &str, second: &str) -> ModeRequest {
+        let prompt = self.prompts.render("compare", &[("language", &self.language), ("first", first), ("second", second)]);
+        ModeRequest::prefixed(&self.config, "compare", &self.language, prompt, 800).keyed_on(&snippet_pair_key(first, second))
+    }
+
+    fn diff_request(&self, diff: &str) -> ModeRequest {
+        let prompt = self.prompts.render("diff", &[("language", &self.language), ("diff", diff)]);
+        ModeRequest::prefixed(&self.config, "diff", &self.language, prompt, 800)
+    }
+
+    fn fix_error_request(&self, code: &str, error: &str) -> ModeRequest {
+        let prompt = self.prompts.render("fixerror", &[("language", &self.language), ("code", code), ("error", error.trim())]);
+        ModeRequest::prefixed(&self.config, "fixerror", &self.language, prompt, 1000).keyed_on(&snippet_pair_key(code, error.trim()))
+    }
+
+    fn diagram_request(&self, code: &str, class_relationships: bool) -> Result<ModeRequest, AssistantError> {
+        let prompt = build_diagram_prompt(&self.prompts, &self.language, code, class_relationships, diagram_format(&self.config)?);
+        Ok(ModeRequest::prefixed(&self.config, "diagram", &self.language, prompt, 800))
+    }
+
+    // Cached under the same keys as the CLI, so the two can share a cache file. Every request stands
+    // alone: there is no conversation, and a truncated response is returned as it is
+    fn request(&self, request: ModeRequest) -> Result<String, AssistantError> {
+        let cache_key = request.full_cache_key();
+        if let Some(response) = self.cached(&cache_key) {
+            return Ok(response);
+        }
+        let reply = self.client.send(&self.payload(request, false))?;
+        self.cache().add_entry(cache_key, reply.text.clone());
+        Ok(reply.text)
+    }
+
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn cached(&self, cache_key: &str) -> Option<String> {
+        self.cache().lookup(cache_key).map(|entry| entry.response.clone())
+    }
+
+    fn payload(&self, request: ModeRequest, stream: bool) -> RequestPayload {
+        let settings = GenerationSettings {
+            model: self.model.clone().or(request.settings.model),
+            temperature: self.temperature.unwrap_or(request.settings.temperature),
+            top_p: request.settings.top_p,
+            max_tokens: self.max_tokens.unwrap_or(request.settings.max_tokens),
+            choices: request.settings.choices,
+        };
+        request_payload(build_messages(&request.system_prompt.text, &[], &request.prompt), &settings, stream)
+    }
+}
+
+/// The same requests without blocking a thread, for callers running on tokio. Each takes `&self`, so
+/// several can be awaited together on one assistant (e.g. with `tokio::join!`) to run a batch at once.
+#[cfg(feature = "async")]
+impl Assistant {
+    pub async fn complete_async(&self, code: &str) -> Result<String, AssistantError> {
+        self.request_async(self.completion_request(code)).await
+    }
+
+    pub async fn complete_in_context_async(&self, context: &str, code: &str) -> Result<String, AssistantError> {
+        self.request_async(self.context_completion_request(context, code)).await
+    }
+
+    pub async fn explain_async(&self, code: &str) -> Result<String, AssistantError> {
+        self.request_async(self.explanation_request(code)).await
+    }
+
+    pub async fn refactor_async(&self, code: &str) -> Result<String, AssistantError> {
+        let mut answer = self.request_async(self.refactoring_request(code)).await?;
+        for _ in 0..self.review_passes {
+            answer = self.request_async(self.critique_request(code, &answer)).await?;
+        }
+        Ok(answer)
+    }
+
+    pub async fn ask_async(&self, question: &str) -> Result<String, AssistantError> {
+        self.request_async(self.question_request(question)).await
+    }
+
+    /// Like `ask_async`, but streams the answer, handing each piece of text to `on_text` as it arrives.
+    /// A cached answer is handed over whole.
+    pub async fn ask_streaming(&self, question: &str, on_text: impl FnMut(&str)) -> Result<String, AssistantError> {
+        self.send_async(self.question_request(question), true, on_text).await
+    }
+
+    pub async fn generate_docs_async(&self, code: &str) -> Result<String, AssistantError> {
+        self.request_async(self.docs_request(code)).await
+    }
+
+    pub async fn compare_async(&self, first: &str, second: &str) -> Result<String, AssistantError> {
+        self.request_async(self.compare_request(first, second)).await
+    }
+
+    pub async fn explain_diff_async(&self, diff: &str) -> Result<String, AssistantError> {
+        self.request_async(self.diff_request(diff)).await
+    }
+
+    pub async fn fix_error_async(&self, code: &str, error: &str) -> Result<String, AssistantError> {
+        self.request_async(self.fix_error_request(code, error)).await
+    }
+
+    pub async fn diagram_async(&self, code: &str, class_relationships: bool) -> Result<String, AssistantError> {
+        self.request_async(self.diagram_request(code, class_relationships)?).await
+    }
+
+    async fn request_async(&self, request: ModeRequest) -> Result<String, AssistantError> {
+        self.send_async(request, false, |_| {}).await
+    }
+
+    // The cache is never locked across an await, so concurrent requests only wait on each other's bookkeeping.
+    // While an identical request is being sent, its answer is waited for and taken from the cache, as batch
+    // workers do; if it didn't produce one, this request is sent after all
+    async fn send_async(&self, request: ModeRequest, stream: bool, mut on_text: impl FnMut(&str)) -> Result<String, AssistantError> {
+        let cache_key = request.full_cache_key();
+        let _claim = loop {
+            if let Some(response) = self.cached(&cache_key) {
+                on_text(&response);
+                return Ok(response);
+            }
+            if let Some(claim) = InFlight::try_claim(&self.in_flight, &cache_key) {
+                break claim;
+            }
+            tokio::time::sleep(IN_FLIGHT_POLL_INTERVAL).await;
+        };
+        let reply = self.async_client.send(&self.payload(request, stream), on_text).await?;
+        self.cache().add_entry(cache_key, reply.text.clone());
+        Ok(reply.text)
+    }
+}
+
+/// Runs the interactive assistant, or a single mode with `--mode`, as the `final_project` binary does.
+/// With `--json`, a failure is also printed to stdout as a JSON object with `"ok": false`.
+pub fn run() -> Result<(), AssistantError> {
+    // Filled in once a `--mode` run knows its mode and language, so a failure names them too
+    let mut json = None;
+    let result = run_assistant(&mut json);
+    if let Err(error) = &result {
+        if json_requested() {
+            print_json_error(json.as_ref(), None, error);
+        }
+    }
+    result
+}
+
+/// Prints `error` to stderr the way the CLI reports failures, with the `Error:` label in red at a terminal.
+pub fn print_error(error: &AssistantError) {
+    error!("{}", error);
+}
+
+fn assume_yes() -> bool {
+    env::args().skip(1).any(|arg| arg == "--yes" || arg == "--force")
+}
+
+fn json_requested() -> bool {
+    env::args().skip(1).any(|arg| arg == "--json")
+}
+
+fn run_assistant(json_output: &mut Option<JsonOutput>) -> Result<(), AssistantError> {
+    NOTICES.store(true, Ordering::Relaxed);
+    // The environment, the .env file and config.toml, remembering where each setting came from
+    let mut config = Config::load();
+    // The flags win over MAX_LINES/MAX_CHARS for this run
+    for (flag, name) in [("--max-lines", "MAX_LINES"), ("--max-chars", "MAX_CHARS")] {
+        if let Some(value) = arg_value(flag) {
+            config = config.with(name, &value);
+        }
+    }
+    // Left out of the help text on purpose: `completions <shell>` prints a tab-completion script and exits
+    if env::args().nth(1).as_deref() == Some("completions") {
+        print!("{}", completion_script(&env::args().nth(2).unwrap_or_default(), &program_name(), &config)?);
+        return Ok(());
+    }
+    // `--json` keeps stdout to the JSON objects alone, so it implies quiet mode
+    let json = json_requested();
+    QUIET.store(json || env::args().skip(1).any(|arg| arg == "--quiet") || config.flag("QUIET"), Ordering::Relaxed);
+    VERBOSE.store(env::args().skip(1).any(|arg| arg == "--verbose") || config.flag("VERBOSE"), Ordering::Relaxed);
+    NO_COLOR.store(config.value("NO_COLOR").is_some_and(|value| !value.is_empty()), Ordering::Relaxed);
+    for path in &config.loaded_from {
+        info!("Loaded settings from {}", path);
+    }
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+    if json && arg_value("--mode").is_none() {
+        return Err(AssistantError::Config("--json needs --mode, since the interactive menu would mix its prompts into the output".to_string()));
+    }
+    if arg_value("--golden").is_some() && (arg_value("--mode").is_none() || arg_value("--batch").is_some()) {
+        return Err(AssistantError::Config("--golden checks the prompts of a single --mode run, without --batch".to_string()));
+    }
+    if arg_value("--out").is_some() && arg_value("--batch").is_none() {
+        return Err(AssistantError::Config("--out names the files of a --batch run; use --output for a single response".to_string()));
+    }
+    // BACKEND=local runs a model on this machine instead, for code that must not leave it
+    let backend: Box<dyn ApiClient> = match config.value("BACKEND").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" | "http" => Box::new(HttpClient { stream_copy: arg_value("--output"), ..http_client(&config)? }),
+        "local" => Box::new(LocalClient::from_config(&config)?),
+        other => return Err(AssistantError::Config(format!("Unknown BACKEND '{}'. Use http or local.", other))),
+    };
+    let client = backend.as_ref();
+
+    // The first Ctrl-C cancels the current action; a second one in quick succession quits as before
+    ctrlc::set_handler(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or(0);
+        let last = LAST_INTERRUPT_MILLIS.swap(now, Ordering::Relaxed);
+        if now.saturating_sub(last) < DOUBLE_INTERRUPT_MILLIS {
+            eprintln!();
+            process::exit(EXIT_CANCELLED);
+        }
+        INTERRUPTED.store(true, Ordering::Relaxed);
+        eprintln!("\nCancelling. Press Enter if waiting for input, or Ctrl-C again to quit.");
+    }).map_err(|error| AssistantError::Config(format!("Could not install the Ctrl-C handler: {}", error)))?;
+
+    // `--yes`/`--force` skips confirmation prompts so destructive actions can be scripted
+    let assume_yes = assume_yes();
+
+    // Load the cache from the file, or from where an earlier version or setting left it
+    let cache_file = cache_filename(&config);
+    let cache_file = cache_file.as_str();
+    let source = cache_source(cache_file, &config);
+    if source != cache_file {
+        info!("Notice: reading the cache from {}; from now on it is saved to {}.", source, cache_file);
+    }
+    let mut cache = load_cache(&source)?;
+    cache.configure(&config);
+
+    // Ask the user to specify the programming language from a predefined list, unless `--lang` names it;
+    // the language picked last time is offered as the default
+    let mut language = match arg_value("--lang") {
+        Some(language) => canonical_language(&config, &language)
+            .ok_or_else(|| AssistantError::Config(format!("Unsupported language '{}'. Use one of: {}.", language, supported_languages(&config).join(", "))))?,
+        // A diff names its files, and asking would eat the first line of one piped in
+        None if arg_value("--mode").as_deref() == Some("explain-diff") => {
+            cache.last_language.clone().unwrap_or_else(|| supported_languages(&config)[0].clone())
+        }
+        None => ask_for_language(&config, cache.last_language.as_deref())?,
+    };
+    cache.set_last_language(&language);
+
+    let mut session = Session {
+        cache: Arc::new(Mutex::new(cache)),
+        in_flight: Arc::new(InFlight::default()),
+        conversation: Conversation::new(config.setting("HISTORY_LIMIT", 20)),
+        code_file: arg_value("--file"),
+        focus_lines: arg_value("--lines"),
+        output_file: arg_value("--output"),
+        clipboard: clipboard_setting(&config),
+        code_only: env::args().skip(1).any(|arg| arg == "--code-only"),
+        highlighter: use_color().then(|| Highlighter::new(&language)),
+        cost_preview: CostPreview {
+            threshold: config.setting("CONFIRM_TOKEN_THRESHOLD", 4000),
+            price_per_1k: config.optional_setting("PRICE_PER_1K_TOKENS"),
+        },
+        // Streamed text can't be wrapped in a JSON object as it arrives
+        stream: !json && (config.flag("STREAM") || env::args().skip(1).any(|arg| arg == "--stream")),
+        tokens_used: Cell::new(0),
+        latencies: RefCell::new(Vec::new()),
+        cache_hits: 0,
+        answered: 0,
+        prompts: PromptTemplates::load(config.value("PROMPTS_FILE").unwrap_or(PROMPTS_FILE))?,
+        json: None,
+        // Scripts and `--yes` keep the old behavior of sending straight away
+        review_prompt: !assume_yes && io::stdin().is_terminal() && config.setting("REVIEW_PROMPT", true),
+        interactive: io::stdin().is_terminal(),
+        assume_yes,
+        dry_run: config.flag("DRY_RUN") || env::args().skip(1).any(|arg| arg == "--dry-run"),
+        golden: arg_value("--golden").map(|path| Golden { path, replace: assume_yes, requests: RefCell::new(Vec::new()) }),
+        review_passes: review_passes_setting(&config)?,
+        cache_enabled: config.setting("CACHE_ENABLED", true) && !env::args().skip(1).any(|arg| arg == "--no-cache"),
+        last_action: None,
+        history_file: Some(history_filename(&config)),
+        config,
+    };
+
+    // `--mode` runs a single action and exits, so the assistant can be used from scripts
+    if let Some(mode) = arg_value("--mode") {
+        if json {
+            session.json = Some(JsonOutput { mode: mode.clone(), language: language.clone() });
+            json_output.clone_from(&session.json);
+        }
+        if !CLI_MODES.contains(&mode.as_str()) {
+            return Err(AssistantError::Config(format!("Unknown mode '{}'. Available modes: {}.", mode, CLI_MODES.join(", "))));
+        }
+        let outcome = match arg_value("--batch") {
+            Some(pattern) => run_batch(&pattern, &mode, client, &language, &mut session),
+            None => run_mode(&mode, client, &language, &mut session),
+        };
+        let checks_prompts = session.golden.is_some();
+        let outcome = outcome.and_then(|()| session.golden.take().map_or(Ok(()), Golden::finish));
+        if session.cache_enabled {
+            save_cache(cache_file, &mut session.cache())?;
+        }
+        // Help and dry runs need no response; any other mode that ends without one gave up along the way
+        return match outcome {
+            Ok(()) if mode == "help" || session.dry_run || checks_prompts || session.answered > 0 => Ok(()),
+            Ok(()) => Err(AssistantError::NoAnswer),
+            Err(error) => Err(error),
+        };
+    }
+
+    loop {
+        say!("AI Code Assistant ({})", language);
+        say!("1. Code Completion");
+        say!("2. Code Explanation");
+        say!("3. Refactoring Suggestions");
+        say!("4. Help: How to Use");
+        say!("5. Explain Compiler Warnings");
+        say!("6. Generate Property-Based Tests");
+        say!("7. Refactoring Plan (Commit by Commit)");
+        say!("8. Find Dead Code");
+        say!("9. Describe Control Flow");
+        say!("10. Teach Me This Pattern");
+        say!("11. Convert Sync/Async Code");
+        if menu_option_available("12", &language) {
+            say!("12. Accessibility & i18n Review (Web)");
+        }
+        say!("13. Suggest Logging & Metrics");
+        say!("14. Migrate to a New Library Version");
+        say!("15. Refactor with Diff");
+        say!("16. Bug & Security Review");
+        say!("17. Ask a Question");
+        say!("18. Translate Code");
+        say!("19. Continue Last Response");
+        say!("20. Code Completion with Context");
+        say!("21. Explain a Diff");
+        say!("22. Generate Docs");
+        say!("23. Compare Two Snippets");
+        say!("24. Fix Compiler Error");
+        say!("25. Draw a Diagram");
+        say!("26. Search History");
+        say!("27. Clear Conversation");
+        say!("28. Change Language");
+        say!("29. View Settings");
+        say!("30. View Cache");
+        say!("31. Show Cached Response");
+        say!("32. Remove Cache Entry");
+        say!("33. Clear Cache");
+        say!("34. Exit");
+        if let Some(last_action) = &session.last_action {
+            say!("r. Repeat option {} (or press Enter)", last_action);
+        }
+        ask!("Choose an option: ");
+
+        // End of input quits, as Exit would, instead of re-showing the menu forever
+        let Some(choice) = read_answer() else {
+            break;
+        };
+        INTERRUPTED.store(false, Ordering::Relaxed);
+
+        // Repeating runs the same action with the same language, so only the new input is asked for
+        let choice = match choice.trim() {
+            "" | "r" | "R" => match &session.last_action {
+                Some(last_action) => last_action.clone(),
+                None => {
+                    say!("Nothing to repeat yet; choose an option from the menu.");
+                    continue;
+                }
+            },
+            choice => choice.to_string(),
+        };
+        if !menu_option_available(&choice, &language) {
+            say!("Invalid option, please try again.");
+            continue;
+        }
+        // The actions that send a request, 1 to 25 apart from the help text
+        if choice.parse::<u32>().is_ok_and(|option| (1..=25).contains(&option) && option != 4) {
+            session.last_action = Some(choice.clone());
+        }
+
+        let outcome = match choice.as_str() {
+            "1" => code_completion(client, &language, &mut session),
+            "2" => code_explanation(client, &language, &mut session),
+            "3" => refactoring_suggestions(client, &language, &mut session),
+            "4" => { help_how_to_use(); Ok(()) }
+            "5" => explain_warnings(client, &language, &mut session),
+            "6" => generate_property_tests(client, &language, &mut session),
+            "7" => refactor_plan(client, &language, &mut session),
+            "8" => find_dead_code(client, &language, &mut session),
+            "9" => describe_flow(client, &language, &mut session),
+            "10" => teach(client, &language, &mut session),
+            "11" => convert_async(client, &language, &mut session),
+            "12" => a11y_review(client, &language, &mut session),
+            "13" => add_logging(client, &language, &mut session),
+            "14" => migrate_version(client, &language, &mut session),
+            "15" => refactor_with_diff(client, &language, &mut session),
+            "16" => security_review(client, &language, &mut session),
+            "17" => ask_question(client, &language, &mut session),
+            "18" => translate_code(client, &language, &mut session),
+            "19" => continue_last_response(client, &language, &mut session),
+            "20" => context_completion(client, &language, &mut session),
+            "21" => explain_diff(client, &language, &mut session),
+            "22" => generate_docs(client, &language, &mut session),
+            "23" => compare_snippets(client, &language, &mut session),
+            "24" => fix_compiler_error(client, &language, &mut session),
+            "25" => draw_diagram(client, &language, &mut session),
+            "26" => search_history(session.history_file.as_deref()),
+            "27" => { clear_conversation(&mut session.conversation); Ok(()) }
+            "28" => change_language(&mut language, &mut session),
+            "29" => { view_settings(&session.config); Ok(()) }
+            "30" => { list_cache(&session.cache()); Ok(()) }
+            "31" => { show_cached_response(&session); Ok(()) }
+            "32" => { remove_cache_entry(&mut session.cache(), session.assume_yes); Ok(()) }
+            "33" => { clear_cache(&mut session.cache(), session.assume_yes); Ok(()) }
+            "34" => break,
+            _ => { say!("Invalid option, please try again."); Ok(()) }
+        };
+        // Written after every action that changed it, so a crash or kill loses at most the current one
+        let mut cache = session.cache();
+        if cache.dirty && session.cache_enabled {
+            save_cache(cache_file, &mut cache)?;
+        }
+        drop(cache);
+        match outcome {
+            Err(AssistantError::Cancelled) => say!("Cancelled, back to the main menu."),
+            Err(error @ AssistantError::TimedOut { .. }) => say!("The {}, back to the main menu.", error),
+            // A failed action shouldn't end the session; only startup problems are fatal
+            Err(error) => error!("{}. Back to the main menu.", error),
+            Ok(()) => {}
+        }
+    }
+
+    // Save the cache to the file before exiting
+    if session.cache_enabled {
+        save_cache(cache_file, &mut session.cache())?;
+    }
+
+    Ok(())
+}
+
+// Switches the language for the rest of the session; the conversation and the cache are kept as they are
+fn change_language(language: &mut String, session: &mut Session) -> Result<(), AssistantError> {
+    let chosen = ask_for_language(&session.config, Some(language))?;
+    if chosen != *language {
+        session.highlighter = use_color().then(|| Highlighter::new(&chosen));
+        session.cache().set_last_language(&chosen);
+        say!("Language changed to {}.", chosen);
+        *language = chosen;
+    }
+    Ok(())
+}
+
+// Runs one action by its `--mode` name
+fn run_mode(mode: &str, client: &dyn ApiClient, language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    match mode {
+        "complete" => code_completion(client, language, session),
+        "complete-context" => context_completion(client, language, session),
+        "explain-diff" => explain_diff(client, language, session),
+        "docs" => generate_docs(client, language, session),
+        "compare" => compare_snippets(client, language, session),
+        "fix-error" => fix_compiler_error(client, language, session),
+        "diagram" => draw_diagram(client, language, session),
+        "explain" => code_explanation(client, language, session),
+        "refactor" => refactoring_suggestions(client, language, session),
+        "help" => { help_how_to_use(); Ok(()) }
+        "warnings" => explain_warnings(client, language, session),
+        "proptest" => generate_property_tests(client, language, session),
+        "refactor-plan" => refactor_plan(client, language, session),
+        "dead-code" => find_dead_code(client, language, session),
+        "flow" => describe_flow(client, language, session),
+        "teach" => teach(client, language, session),
+        "async" => convert_async(client, language, session),
+        "a11y" => a11y_review(client, language, session),
+        "logging" => add_logging(client, language, session),
+        "migrate" => migrate_version(client, language, session),
+        "refactor-diff" => refactor_with_diff(client, language, session),
+        "review" => security_review(client, language, session),
+        "ask" => ask_question(client, language, session),
+        "translate" => translate_code(client, language, session),
+        "continue" => continue_last_response(client, language, session),
+        _ => Err(AssistantError::Config(format!("Unknown mode '{}'. Available modes: {}.", mode, CLI_MODES.join(", ")))),
+    }
+}
+
+// Runs one mode over every file matching a glob, or every file in a directory, writing each
+// response next to its file as `<file>.<mode>.md`, or where the `--out` template puts it
+fn run_batch(pattern: &str, mode: &str, client: &dyn ApiClient, language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let pattern = if Path::new(pattern).is_dir() {
+        format!("{}/*", pattern.trim_end_matches('/'))
+    } else {
+        pattern.to_string()
+    };
+    let output_suffix = format!(".{}.md", mode);
+    let files: Vec<_> = glob::glob(&pattern).map_err(|error| AssistantError::Config(format!("Invalid --batch pattern '{}': {}", pattern, error)))?
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file() && !path.to_string_lossy().ends_with(&output_suffix))
+        .collect();
+    let template = arg_value("--out");
+    let outputs: Vec<String> = match &template {
+        Some(template) => {
+            check_output_template(template)?;
+            files.iter().map(|file| batch_output_path(template, file, mode)).collect()
+        }
+        None => files.iter().map(|file| format!("{}{}", file.display(), output_suffix)).collect(),
+    };
+    // Checked up front, since two workers writing the same file would race
+    let mut claimed = HashMap::new();
+    for (file, output) in files.iter().zip(&outputs) {
+        if let Some(other) = claimed.insert(output, file) {
+            return Err(AssistantError::Config(format!(
+                "--out puts both {} and {} in {}; use {{dir}} or {{stem}} to tell them apart",
+                other.display(), file.display(), output
+            )));
+        }
+    }
+    // Templated outputs may land anywhere, so existing files are only replaced once that is confirmed
+    let existing = outputs.iter().filter(|output| Path::new(output).exists()).count();
+    let overwrite = template.is_none() || existing == 0
+        || (session.interactive || session.assume_yes) && confirm(&format!("{} of the --out files already exist. Overwrite them?", existing), session.assume_yes);
+
+    // Up to CONCURRENCY workers take the next file until none are left; rate limits are still handled
+    // by each request's retries with backoff, drawing on the session's shared retry budget
+    let concurrency = session.config.setting("CONCURRENCY", DEFAULT_CONCURRENCY).clamp(1, files.len().max(1));
+    let started = Instant::now();
+    let next = AtomicUsize::new(0);
+    let workers: Vec<Session> = (0..concurrency).map(|_| session.worker()).collect();
+    let results: Vec<(BatchCounts, Session)> = thread::scope(|scope| {
+        let handles: Vec<_> = workers.into_iter()
+            .map(|mut worker| {
+                let (files, outputs, next) = (&files, &outputs, &next);
+                scope.spawn(move || {
+                    let mut counts = BatchCounts::default();
+                    loop {
+                        let number = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(file) = files.get(number) else {
+                            break;
+                        };
+                        if INTERRUPTED.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        say!("[{}/{}] {}", number + 1, files.len(), file.display());
+                        counts.add(run_batch_file(&file.display().to_string(), &outputs[number], overwrite, mode, client, language, &mut worker));
+                    }
+                    (counts, worker)
+                })
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().expect("batch worker panicked")).collect()
+    });
+
+    let mut totals = BatchCounts::default();
+    let mut cache_hits = 0;
+    let mut latencies = Vec::new();
+    for (counts, worker) in results {
+        totals.processed += counts.processed;
+        totals.skipped += counts.skipped;
+        totals.errors += counts.errors;
+        cache_hits += worker.cache_hits;
+        session.cache_hits += worker.cache_hits;
+        session.answered += worker.answered;
+        session.tokens_used.set(session.tokens_used.get() + worker.tokens_used.get());
+        latencies.extend(worker.latencies.into_inner());
+    }
+    let BatchCounts { processed, skipped, errors } = totals;
+    say!("Batch finished: {} processed, {} cache hits, {} errors, {} skipped.", processed, cache_hits, errors, skipped);
+    match latency_summary(&mut latencies) {
+        Some(summary) => say!("Latency: {}; total time {}.", summary, format_duration(started.elapsed())),
+        None => say!("Total time {}.", format_duration(started.elapsed())),
+    }
+    if errors > 0 {
+        return Err(AssistantError::BatchFailed { failed: errors, total: files.len() });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct BatchCounts {
+    processed: u32,
+    skipped: u32,
+    errors: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BatchOutcome {
+    Processed,
+    Skipped,
+    Failed,
+}
+
+impl BatchCounts {
+    fn add(&mut self, outcome: BatchOutcome) {
+        match outcome {
+            BatchOutcome::Processed => self.processed += 1,
+            BatchOutcome::Skipped => self.skipped += 1,
+            BatchOutcome::Failed => self.errors += 1,
+        }
+    }
+}
+
+fn run_batch_file(file: &str, output: &str, overwrite: bool, mode: &str, client: &dyn ApiClient, language: &str, session: &mut Session) -> BatchOutcome {
+    let modified = |path: &str| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let previous_output = modified(output);
+    if previous_output.is_some() && !overwrite {
+        warn!("{} already exists, skipping {} (pass --force to overwrite it).", output, file);
+        return BatchOutcome::Skipped;
+    }
+    if let Some(parent) = Path::new(output).parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Err(error) = fs::create_dir_all(parent) {
+            error!("{}: could not create {}: {}", file, parent.display(), error);
+            return BatchOutcome::Failed;
+        }
+    }
+    // Each file stands alone, so neither the conversation nor the previous file's paths carry over
+    session.conversation.clear();
+    session.code_file = Some(file.to_string());
+    session.output_file = Some(output.to_string());
+    match run_mode(mode, client, language, session) {
+        // A mode that gave up without an answer, e.g. on a language mismatch, leaves the output untouched
+        Ok(()) if modified(output) != previous_output => BatchOutcome::Processed,
+        Ok(()) => BatchOutcome::Skipped,
+        Err(error) => {
+            error!("{}: {}", file, error);
+            if let Some(json) = &session.json {
+                print_json_error(Some(json), Some(file), &error);
+            }
+            BatchOutcome::Failed
+        }
+    }
+}
+
+const OUTPUT_PLACEHOLDERS: [&str; 4] = ["dir", "stem", "ext", "mode"];
+
+// Fills in an `--out` template for one batch file: `{dir}` is the file's directory (`.` for none), `{stem}`
+// its name without the extension, `{ext}` the extension without the dot and `{mode}` the mode
+fn batch_output_path(template: &str, file: &Path, mode: &str) -> String {
+    let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let part = |part: Option<&std::ffi::OsStr>| part.map(|part| part.to_string_lossy().into_owned()).unwrap_or_default();
+    let values = [dir.to_string_lossy().into_owned(), part(file.file_stem()), part(file.extension()), mode.to_string()];
+    // One pass, so a file name that happens to contain `{mode}` is left alone
+    let mut output = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        let tail = &rest[start + 1..];
+        match tail.find('}').and_then(|end| Some((end, OUTPUT_PLACEHOLDERS.iter().position(|name| *name == &tail[..end])?))) {
+            Some((end, index)) => {
+                output.push_str(&values[index]);
+                rest = &tail[end + 1..];
+            }
+            None => {
+                output.push('{');
+                rest = tail;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+// A misspelled placeholder would otherwise end up in every file name
+fn check_output_template(template: &str) -> Result<(), AssistantError> {
+    let unknown = template.split('{').skip(1)
+        .filter_map(|part| part.split_once('}').map(|(name, _)| name))
+        .find(|name| !OUTPUT_PLACEHOLDERS.contains(name));
+    match unknown {
+        Some(name) => Err(AssistantError::Config(format!(
+            "Unknown placeholder {{{}}} in --out; use {}",
+            name, OUTPUT_PLACEHOLDERS.map(|name| format!("{{{}}}", name)).join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn ask_for_language(config: &Config, last_language: Option<&str>) -> Result<String, AssistantError> {
+    // A remembered language that is no longer supported is simply not offered
+    let last_language = last_language.and_then(|language| canonical_language(config, language));
+    loop {
+        say!("Please specify the programming language you are using ({}):", supported_languages(config).join(", "));
+        match &last_language {
+            Some(last) => ask!("Use {} again? (Enter to accept, or type another language): ", last),
+            None => ask!("Enter your programming language: "),
+        }
+
+        // Without a default there is nothing to fall back on once input ends, and asking again would loop forever
+        let language = match read_answer() {
+            Some(language) => language,
+            None if last_language.is_none() => return Err(AssistantError::Input("No language given.".to_string())),
+            None => String::new(),
+        };
+        if let (Some(last), "") = (&last_language, language.trim()) {
+            return Ok(last.clone());
+        }
+
+        if let Some(language) = canonical_language(config, &language) {
+            return Ok(language);
+        } else {
+            say!("Invalid language. Please enter one of the following: {}.", supported_languages(config).join(", "));
+        }
+    }
+}
+
+// Maps any casing of a supported language to its canonical spelling, e.g. "javascript" -> "JavaScript"
+fn canonical_language(config: &Config, input: &str) -> Option<String> {
+    let input = input.trim();
+    supported_languages(config).into_iter().find(|lang| lang.eq_ignore_ascii_case(input))
+}
+
+// SUPPORTED_LANGUAGES as a comma-separated list, e.g. "Rust, Go, TypeScript"; DEFAULT_LANGUAGES when unset or empty
+fn supported_languages(config: &Config) -> Vec<String> {
+    let configured: Vec<String> = config.value("SUPPORTED_LANGUAGES")
+        .unwrap_or_default()
+        .split(',')
+        .map(|language| language.trim().to_string())
+        .filter(|language| !language.is_empty())
+        .collect();
+    if configured.is_empty() {
+        DEFAULT_LANGUAGES.iter().map(|language| language.to_string()).collect()
+    } else {
+        configured
+    }
+}
+
+// Verbose languages and big files need more room for the same task; short scripts need less
+fn language_token_factor(language: &str) -> f64 {
+    LANGUAGE_TOKEN_FACTORS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(language))
+        .map_or(1.0, |&(_, factor)| factor)
+}
+
+// The language as it appears in a variable name: `C++` is `CPP`, `C#` is `CSHARP`
+fn language_variable_name(language: &str) -> String {
+    language.to_ascii_uppercase()
+        .replace('+', "P")
+        .replace('#', "SHARP")
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect()
+}
+
+// Checks a response before it is accepted; failures are regenerated a bounded number of times.
+// Send, so a request can be held across an await by the async backend
+trait ResponseValidator: Send {
+    fn validate(&self, response: &str) -> Result<(), String>;
+}
+
+// For modes whose answer is code: the response has to contain a fenced code block
+struct CodeFenceValidator;
+
+impl ResponseValidator for CodeFenceValidator {
+    fn validate(&self, response: &str) -> Result<(), String> {
+        if response.lines().filter(|line| line.trim_start().starts_with("```")).count() >= 2 {
+            Ok(())
+        } else {
+            Err("no fenced code block".to_string())
+        }
+    }
+}
+
+// Stable 64-bit FNV-1a hash, used where a short fingerprint has to survive across runs
+fn fnv1a_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Prints the cached response for a request, or asks the API and caches the answer
+fn respond(client: &dyn ApiClient, session: &mut Session, request: ModeRequest) -> Result<(), AssistantError> {
+    if let Some(reply) = fetch(client, session, request)? {
+        deliver_reply(session, &reply)?;
+    }
+    Ok(())
+}
+
+// Prints a reply and, when `--output` was given, also writes it to that file
+fn deliver_reply(session: &Session, reply: &Reply) -> Result<(), AssistantError> {
+    match &session.json {
+        Some(json) => print_json_reply(json, reply, &reply.text)?,
+        None => print_reply(reply, session),
+    }
+    write_output(session, &reply.text)
+}
+
+fn print_json_failure(json: Option<&JsonOutput>, file: Option<&str>, kind: &'static str, message: String, status: Option<u16>) {
+    let failure = JsonFailure {
+        ok: false,
+        mode: json.map(|json| json.mode.as_str()),
+        language: json.map(|json| json.language.as_str()),
+        file,
+        error: JsonError { kind, message, status },
+    };
+    // Plain strings and numbers, which always serialize
+    println!("{}", serde_json::to_string(&failure).unwrap_or_default());
+}
+
+fn print_json_error(json: Option<&JsonOutput>, file: Option<&str>, error: &AssistantError) {
+    let status = match error {
+        AssistantError::Http { status } => Some(*status),
+        _ => None,
+    };
+    print_json_failure(json, file, error.kind(), error.to_string(), status);
+}
+
+// `response` is what the mode shows, which may be derived from the reply, such as a diff
+fn print_json_reply(json: &JsonOutput, reply: &Reply, response: &str) -> Result<(), AssistantError> {
+    let finish_reason = match (reply.cached, reply.truncated) {
+        (true, _) => None,
+        (false, true) => Some("length"),
+        (false, false) => Some("stop"),
+    };
+    let output = JsonReply {
+        ok: true,
+        mode: &json.mode,
+        language: &json.language,
+        prompt_hash: reply.prompt_hash.as_deref(),
+        response,
+        cached: reply.cached,
+        finish_reason,
+        usage: reply.usage,
+        elapsed_ms: reply.elapsed.map(|elapsed| elapsed.as_millis() as u64),
+    };
+    println!("{}", serde_json::to_string(&output)?);
+    Ok(())
+}
+
+fn write_output(session: &Session, text: &str) -> Result<(), AssistantError> {
+    if let Some(path) = &session.output_file {
+        fs::write(path, text)?;
+        say!("Response written to {}.", path);
+    }
+    if let Some(copy) = session.clipboard {
+        let blocks = extract_code_blocks(text);
+        let content = if copy == ClipboardCopy::Code && !blocks.is_empty() { blocks.join("\n\n") } else { text.to_string() };
+        // Without a clipboard, e.g. over SSH or in CI, the printed response is all there is
+        match copy_to_clipboard(&content) {
+            Ok(()) => say!("Copied to the clipboard."),
+            Err(reason) => warn!("could not copy to the clipboard ({}); the response is printed above.", reason),
+        }
+    }
+    Ok(())
+}
+
+// What `--clipboard` or CLIPBOARD copies after each response
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClipboardCopy {
+    Response,
+    Code, // Just the fenced code blocks, or the whole response when it has none
+}
+
+// `--clipboard` copies responses; CLIPBOARD=true does the same and CLIPBOARD=code copies only their code
+fn clipboard_setting(config: &Config) -> Option<ClipboardCopy> {
+    let flag = env::args().skip(1).any(|arg| arg == "--clipboard");
+    match config.value("CLIPBOARD").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" | "false" | "0" => flag.then_some(ClipboardCopy::Response),
+        "true" | "1" | "response" => Some(ClipboardCopy::Response),
+        "code" => Some(ClipboardCopy::Code),
+        other => {
+            warn!("CLIPBOARD={} is not valid (use true or code), ignoring it.", other);
+            flag.then_some(ClipboardCopy::Response)
+        }
+    }
+}
+
+// Hands the text to the platform's clipboard command: pbcopy, clip, or on Linux wl-copy, xclip or xsel,
+// whichever is installed for the display in use
+fn copy_to_clipboard(text: &str) -> Result<(), String> {
+    let commands: Vec<(&str, &[&str])> = if cfg!(target_os = "macos") {
+        vec![("pbcopy", &[])]
+    } else if cfg!(windows) {
+        vec![("clip", &[])]
+    } else {
+        let mut commands: Vec<(&str, &[&str])> = Vec::new();
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            commands.push(("wl-copy", &[]));
+        }
+        if env::var_os("DISPLAY").is_some() {
+            commands.push(("xclip", &["-selection", "clipboard"]));
+            commands.push(("xsel", &["--clipboard", "--input"]));
+        }
+        commands
+    };
+    if commands.is_empty() {
+        return Err("no display is available".to_string());
+    }
+    for (program, args) in &commands {
+        let Ok(mut child) = process::Command::new(program).args(*args)
+            .stdin(process::Stdio::piped()).stdout(process::Stdio::null()).stderr(process::Stdio::null())
+            .spawn() else {
+            continue;
+        };
+        let written = child.stdin.take().map(|mut stdin| stdin.write_all(text.as_bytes()));
+        if matches!(written, Some(Ok(()))) && child.wait().is_ok_and(|status| status.success()) {
+            return Ok(());
+        }
+    }
+    let programs: Vec<&str> = commands.iter().map(|(program, _)| *program).collect();
+    Err(format!("no working clipboard command; tried {}", programs.join(", ")))
+}
+
+// On a terminal, prose is wrapped to its width and long responses are paged
+fn print_reply(reply: &Reply, session: &Session) {
+    if reply.streamed {
+        return;
+    }
+    let size = terminal_size::terminal_size();
+    let text = match size {
+        Some((terminal_size::Width(width), _)) => wrap_prose(&reply.text, width as usize),
+        None => reply.text.clone(),
+    };
+    let text = match &session.highlighter {
+        Some(highlighter) => highlighter.highlight(&text),
+        None => text,
+    };
+    match size {
+        Some((_, terminal_size::Height(height))) if use_pager(&session.config) => page(&text, height as usize),
+        _ => println!("{}", text),
+    }
+}
+
+// Word-wraps every line outside fenced code blocks, so indentation in code is never touched;
+// continuation lines keep the indentation of the line they came from, including a list marker's
+fn wrap_prose(text: &str, width: usize) -> String {
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        let fence = line.trim_start().starts_with("```");
+        if fence {
+            in_code = !in_code;
+        }
+        if in_code || fence || line.chars().count() <= width {
+            lines.push(line.to_string());
+            continue;
+        }
+        let body = line.trim_start();
+        let indent = line.len() - body.len();
+        let marker = ["- ", "* ", "+ "].iter()
+            .find(|marker| body.starts_with(**marker))
+            .map(|marker| marker.len())
+            .or_else(|| {
+                let digits = body.chars().take_while(char::is_ascii_digit).count();
+                (digits > 0 && body[digits..].starts_with(". ")).then_some(digits + 2)
+            })
+            .unwrap_or(0);
+        let continuation = " ".repeat(indent + marker);
+        let mut current = line[..indent].to_string();
+        let mut current_width = indent;
+        let mut has_word = false;
+        // A word wider than the terminal gets a line of its own rather than being split
+        for word in body.split_whitespace() {
+            let word_width = word.chars().count();
+            if has_word && current_width + 1 + word_width > width {
+                lines.push(std::mem::replace(&mut current, continuation.clone()));
+                current_width = continuation.len();
+                has_word = false;
+            }
+            if has_word {
+                current.push(' ');
+                current_width += 1;
+            }
+            current.push_str(word);
+            current_width += word_width;
+            has_word = true;
+        }
+        lines.push(current);
+    }
+    lines.join("\n")
+}
+
+// Paging needs someone at the terminal to press Enter; NO_PAGER turns it off
+fn use_pager(config: &Config) -> bool {
+    io::stdout().is_terminal() && io::stdin().is_terminal() && config.value("NO_PAGER").is_none_or(str::is_empty)
+}
+
+// Shows a screenful at a time; Enter shows the next one and `q` skips the rest
+fn page(text: &str, height: usize) {
+    let lines: Vec<&str> = text.lines().collect();
+    let page_size = height.saturating_sub(1).max(1);
+    for (index, chunk) in lines.chunks(page_size).enumerate() {
+        if index > 0 {
+            print!("-- more (Enter to continue, q to stop) --");
+            io::stdout().flush().unwrap();
+            if read_answer().is_none_or(|answer| answer.trim().eq_ignore_ascii_case("q")) || INTERRUPTED.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+        println!("{}", chunk.join("\n"));
+    }
+}
+
+// Returns the cached response for a request, or asks the API and caches the answer;
+// None means the user cancelled the request
+fn fetch(client: &dyn ApiClient, session: &mut Session, request: ModeRequest) -> Result<Option<Reply>, AssistantError> {
+    let Some(request) = guard_secrets(request, session)? else {
+        return Ok(None);
+    };
+    // Reviewed before the cache lookup, since added instructions make it a different request
+    let request = if session.review_prompt {
+        let Some(prompt) = review_prompt(&request.prompt)? else {
+            say!("Request cancelled.");
+            return Ok(None);
+        };
+        // The key ends with the prompt, after the mode prefix when there is one
+        let prefix = request.cache_key.strip_suffix(request.prompt.as_str()).unwrap_or_default();
+        ModeRequest { cache_key: format!("{}{}", prefix, prompt), prompt, ..request }
+    } else {
+        request
+    };
+    let mut cache_key = request.full_cache_key();
+    // A follow-up may mean something else in another conversation, so the history is part of the key too
+    if !session.conversation.messages.is_empty() {
+        cache_key = format!("{}#history:{:016x}", cache_key, session.conversation.fingerprint());
+    }
+
+    let messages = build_messages(&request.system_prompt.text, &session.conversation.messages, &request.prompt);
+
+    // A dry run shows exactly what would be sent and stops there, leaving the cache and the API alone
+    if session.dry_run {
+        println!("{}", serde_json::to_string_pretty(&request_payload(messages, &request.settings, session.stream))?);
+        return Ok(None);
+    }
+
+    // When another batch worker is already sending the same request, its answer is waited for and then
+    // taken from the cache; if it didn't produce one, this worker sends the request itself.
+    // With the cache disabled every request is sent, so there is nothing to wait for.
+    // A golden check skips the cache too, since a cached answer would hide the prompt
+    let _in_flight = if session.cache_enabled && session.golden.is_none() {
+        loop {
+            let cached = session.cache().lookup(&cache_key).map(|entry| entry.response.clone());
+            if let Some(response) = cached {
+                info!("Using cached response:");
+                session.cache_hits += 1;
+                session.answered += 1;
+                let reply = Reply {
+                    text: response,
+                    streamed: false,
+                    truncated: false,
+                    usage: None,
+                    cached: true,
+                    prompt_hash: Some(prompt_hash(&cache_key)),
+                    alternatives: Vec::new(),
+                    elapsed: None,
+                };
+                session.conversation.record(request.prompt, reply.text.clone());
+                return Ok(Some(reply));
+            }
+            if let Some(failure) = session.cache().recent_failure(&cache_key) {
+                warn!("This request failed {}s ago and would fail again, so it was not resent (cached failure): {}", now_secs().saturating_sub(failure.failed_at), failure.message);
+                return Ok(None);
+            }
+            if let Some(claim) = InFlight::claim(&session.in_flight, &cache_key) {
+                break Some(claim);
+            }
+        }
+    } else {
+        None
+    };
+
+    let mut regenerations = 0;
+    loop {
+        let reply = match send_messages(client, session, messages.clone(), &request.settings) {
+            Ok(Some(reply)) => {
+                append_history(session.history_file.as_deref(), &request.mode, &request.language, &request.prompt, &reply);
+                reply
+            }
+            Ok(None) => return Ok(None),
+            Err(error) => {
+                if error.is_permanent() && session.cache_enabled {
+                    session.cache().record_failure(&cache_key, error.to_string());
+                }
+                return Err(error);
+            }
+        };
+        match request.validator.as_ref().map(|validator| validator.validate(&reply.text)) {
+            Some(Err(reason)) if regenerations < MAX_REGENERATIONS => {
+                warn!("The response was rejected ({}), regenerating...", reason);
+                regenerations += 1;
+            }
+            Some(Err(reason)) => {
+                // Still shown, but not cached, so the next attempt asks the API again
+                warn!("the response still failed validation ({}).", reason);
+                session.conversation.record(request.prompt, reply.text.clone());
+                session.answered += 1;
+                return Ok(Some(reply));
+            }
+            _ => {
+                let reply = Reply { prompt_hash: Some(prompt_hash(&cache_key)), ..reply };
+                if session.cache_enabled {
+                    session.cache().add_entry(cache_key, reply.text.clone());
+                }
+                session.conversation.record(request.prompt, reply.text.clone());
+                session.answered += 1;
+                return Ok(Some(reply));
+            }
+        }
+    }
+}
+
+fn history_filename(config: &Config) -> String {
+    config.value("HISTORY_FILE").unwrap_or(HISTORY_FILE).to_string()
+}
+
+// Appends one line per answered request; a history that can't be written only warns, since the answer still arrived
+fn append_history(history_file: Option<&str>, mode: &str, language: &str, prompt: &str, reply: &Reply) {
+    let Some(filename) = history_file else {
+        return;
+    };
+    let entry = HistoryEntry {
+        timestamp: now_secs(),
+        mode: mode.to_string(),
+        language: language.to_string(),
+        prompt: prompt.to_string(),
+        response: reply.text.clone(),
+        usage: reply.usage,
+        elapsed_ms: reply.elapsed.map(|elapsed| elapsed.as_millis() as u64),
+    };
+    let written = serde_json::to_string(&entry).map_err(io::Error::from).and_then(|line| {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(filename)?;
+        writeln!(file, "{}", line)
+    });
+    if let Err(error) = written {
+        warn!("could not append to the history file '{}': {}", filename, error);
+    }
+}
+
+// The newest entry in the history file that can be read
+fn last_history_entry(history_file: Option<&str>) -> Option<HistoryEntry> {
+    let content = fs::read_to_string(history_file?).ok()?;
+    content.lines().rev().find_map(|line| serde_json::from_str(line).ok())
+}
+
+// Case-insensitive substring search over the prompts and responses in the history file, newest first
+fn search_history(history_file: Option<&str>) -> Result<(), AssistantError> {
+    let Some(filename) = history_file else {
+        say!("No history is kept in this session.");
+        return Ok(());
+    };
+    let content = match fs::read_to_string(filename) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            say!("No history yet; it is written to '{}' after each answered request.", filename);
+            return Ok(());
+        }
+        Err(error) => return Err(error.into()),
+    };
+    let query = prompt_line("Search the history for: ").to_lowercase();
+    check_cancelled()?;
+    if query.is_empty() {
+        return Ok(());
+    }
+    // A line that can't be parsed, such as one cut short by a crash, is skipped rather than failing the search
+    let matches: Vec<HistoryEntry> = content.lines()
+        .filter_map(|line| serde_json::from_str::<HistoryEntry>(line).ok())
+        .filter(|entry| entry.prompt.to_lowercase().contains(&query) || entry.response.to_lowercase().contains(&query))
+        .collect();
+    if matches.is_empty() {
+        say!("No requests in the history match '{}'.", query);
+        return Ok(());
+    }
+    let now = now_secs();
+    for entry in matches.iter().rev() {
+        say!("[{}] {} ({})", cached_age(now, entry.timestamp), entry.mode, entry.language);
+        say!("Prompt: {}", one_line_preview(&entry.prompt, 100));
+        say!("Response: {}", one_line_preview(&entry.response, 200));
+        say!();
+    }
+    say!("{} matching requests.", matches.len());
+    Ok(())
+}
+
+// Shows the assembled prompt and returns it as it should be sent, or None if the user cancels
+fn review_prompt(prompt: &str) -> Result<Option<String>, AssistantError> {
+    say!("Prompt to send:\n{}", prompt);
+    loop {
+        let answer = prompt_line("Press Enter to send, type extra instructions to append to it, 'e' to edit it in $EDITOR, or 'n' to cancel: ");
+        check_cancelled()?;
+        return match answer.as_str() {
+            "" => Ok(Some(prompt.to_string())),
+            "n" | "N" => Ok(None),
+            "e" | "E" => match edit_in_editor(prompt) {
+                // Saving an empty file cancels, as with `git commit`
+                Ok(edited) if edited.trim().is_empty() => Ok(None),
+                Ok(edited) => Ok(Some(edited)),
+                Err(error) => {
+                    say!("{}", error);
+                    continue;
+                }
+            },
+            instructions => Ok(Some(format!("{}\n\n{}", prompt, instructions))),
+        };
+    }
+}
+
+// SECRET_SCAN: `warn` (the default) reports secrets in a prompt and offers to redact them, `block` refuses
+// to send it and `off` skips the scan
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SecretScan {
+    Off,
+    Warn,
+    Block,
+}
+
+fn secret_scan(config: &Config) -> SecretScan {
+    match config.value("SECRET_SCAN").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" | "warn" => SecretScan::Warn,
+        "block" => SecretScan::Block,
+        "off" => SecretScan::Off,
+        other => {
+            warn!("SECRET_SCAN={} is not valid (use warn, block or off), using warn.", other);
+            SecretScan::Warn
+        }
+    }
+}
+
+// Looks for credentials in the prompt before it leaves the machine. Without a terminal to ask,
+// `warn` only reports them
+fn guard_secrets(request: ModeRequest, session: &Session) -> Result<Option<ModeRequest>, AssistantError> {
+    let scan = secret_scan(&session.config);
+    if scan == SecretScan::Off {
+        return Ok(Some(request));
+    }
+    let findings = find_secrets(&request.prompt);
+    if findings.is_empty() {
+        return Ok(Some(request));
+    }
+    warn!("the prompt looks like it contains secrets:");
+    for finding in &findings {
+        let line = request.prompt[..finding.start].matches('\n').count() + 1;
+        let preview: String = request.prompt[finding.start..finding.end].chars().take(4).collect();
+        eprintln!("  line {} of the prompt: {} ({}...)", line, finding.kind, preview);
+    }
+    if scan == SecretScan::Block {
+        return Err(AssistantError::Input("The request was not sent, since SECRET_SCAN=block. Remove the secrets or set SECRET_SCAN=warn to redact them.".to_string()));
+    }
+    if !session.interactive {
+        eprintln!("Sending it unchanged; run interactively to redact them, or set SECRET_SCAN=block to refuse.");
+        return Ok(Some(request));
+    }
+    let answer = prompt_line("Redact them before sending? [Y/n]: ");
+    check_cancelled()?;
+    if matches!(answer.to_ascii_lowercase().as_str(), "n" | "no") {
+        return Ok(Some(request));
+    }
+    let mut prompt = request.prompt.clone();
+    // From the end, so the earlier offsets stay valid
+    for finding in findings.iter().rev() {
+        prompt.replace_range(finding.start..finding.end, "[REDACTED]");
+    }
+    // The key ends with the prompt, after the mode prefix when there is one
+    let prefix = request.cache_key.strip_suffix(request.prompt.as_str()).unwrap_or_default();
+    Ok(Some(ModeRequest { cache_key: format!("{}{}", prefix, prompt), prompt, ..request }))
+}
+
+// A likely secret, as a byte range of the scanned text
+#[derive(Debug)]
+struct SecretFinding {
+    kind: &'static str,
+    start: usize,
+    end: usize,
+}
+
+// Private key blocks, AWS access key ids, well-known token prefixes, credentials assigned to names like
+// `password` or `api_key`, and long random-looking tokens. Overlapping findings keep the earliest
+fn find_secrets(text: &str) -> Vec<SecretFinding> {
+    let mut findings = Vec::new();
+    let mut search = 0;
+    while let Some(offset) = text[search..].find("-----BEGIN ") {
+        let start = search + offset;
+        let header_end = text[start..].find('\n').map_or(text.len(), |end| start + end);
+        if text[start..header_end].contains("PRIVATE KEY") {
+            let end = text[start..].find("-----END ")
+                .and_then(|footer| text[start + footer..].find("KEY-----").map(|key| start + footer + key + "KEY-----".len()))
+                .unwrap_or(header_end);
+            findings.push(SecretFinding { kind: "private key", start, end });
+            search = end;
+        } else {
+            search = header_end;
+        }
+    }
+
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "_-+/=".contains(c);
+    let mut start = None;
+    for (position, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (start, is_token_char(c)) {
+            (None, true) => start = Some(position),
+            (Some(begin), false) => {
+                if let Some(kind) = token_secret_kind(&text[begin..position]) {
+                    findings.push(SecretFinding { kind, start: begin, end: position });
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        if let Some((start, end)) = credential_assignment(line) {
+            findings.push(SecretFinding { kind: "credential assignment", start: line_start + start, end: line_start + end });
+        }
+        line_start += line.len();
+    }
+
+    findings.sort_by_key(|finding| finding.start);
+    let mut kept: Vec<SecretFinding> = Vec::new();
+    for finding in findings {
+        if kept.last().is_none_or(|last| finding.start >= last.end) {
+            kept.push(finding);
+        }
+    }
+    kept
+}
+
+fn token_secret_kind(token: &str) -> Option<&'static str> {
+    let aws = (token.starts_with("AKIA") || token.starts_with("ASIA"))
+        && token.len() == 20
+        && token[4..].chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit());
+    if aws {
+        Some("AWS access key")
+    } else if ["ghp_", "gho_", "github_pat_", "xoxb-", "xoxp-", "sk-"].iter().any(|prefix| token.starts_with(prefix)) && token.len() >= 24 {
+        Some("API token")
+    } else if token.len() >= 32 && token.chars().any(|c| c.is_ascii_digit()) && token.chars().any(|c| c.is_ascii_alphabetic()) && shannon_entropy(token) > 4.2 {
+        // Hex digests top out at 4 bits per character, so hashes and ids stay below the threshold
+        Some("high-entropy token")
+    } else {
+        None
+    }
+}
+
+// Bits per character of the token's character distribution
+fn shannon_entropy(token: &str) -> f64 {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in token.chars() {
+        *counts.entry(c).or_default() += 1;
+    }
+    let length = token.chars().count() as f64;
+    counts.values().map(|&count| count as f64 / length).map(|share| -share * share.log2()).sum()
+}
+
+// The value's byte range in `line` when it assigns a literal to a credential-like name: `password = "..."`,
+// `"api_key": "..."` or `DB_PASSWORD=...` as in a .env file. Comparisons and computed values are left alone
+fn credential_assignment(line: &str) -> Option<(usize, usize)> {
+    const NAMES: [&str; 7] = ["password", "passwd", "secret", "api_key", "apikey", "access_key", "token"];
+    let separator = line.find(['=', ':'])?;
+    if line[separator + 1..].starts_with(['=', ':']) {
+        return None;
+    }
+    let before = line[..separator].trim_end().trim_end_matches(['"', '\'']);
+    let name = before.rsplit(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).next()?.to_ascii_lowercase();
+    if !NAMES.iter().any(|credential| name.contains(credential)) {
+        return None;
+    }
+    let value_start = separator + 1 + (line[separator + 1..].len() - line[separator + 1..].trim_start().len());
+    let rest = &line[value_start..];
+    let (start, end) = match rest.chars().next()? {
+        quote @ ('"' | '\'' | '`') => {
+            let length = rest[1..].find(quote)?;
+            (value_start + 1, value_start + 1 + length)
+        }
+        // A bare value only counts on a `NAME=value` line, and only if it can't be an expression
+        _ if !before.contains(char::is_whitespace) => {
+            let length = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let value = &rest[..length];
+            if value.contains(['(', ')', '[', '{', '.']) {
+                return None;
+            }
+            (value_start, value_start + length)
+        }
+        _ => return None,
+    };
+    (end - start >= 4).then_some((start, end))
+}
+
+// Opens the prompt in $VISUAL or $EDITOR (vi if neither is set) and reads back what was saved
+fn edit_in_editor(text: &str) -> Result<String, AssistantError> {
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    let path = env::temp_dir().join(format!("ai_code_assistant_prompt_{}.txt", process::id()));
+    fs::write(&path, text)?;
+    // Editors like `code --wait` come with arguments
+    let mut words = editor.split_whitespace();
+    let program = words.next().ok_or_else(|| AssistantError::Config("EDITOR is empty".to_string()))?;
+    let status = process::Command::new(program).args(words).arg(&path).status();
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    match status {
+        Ok(status) if status.success() => Ok(edited?.trim_end().to_string()),
+        Ok(status) => Err(AssistantError::Input(format!("The editor '{}' exited with {}.", editor, status))),
+        Err(error) => Err(AssistantError::Config(format!("Could not start the editor '{}': {}", editor, error))),
+    }
+}
+
+// The messages of one request: the system prompt, the conversation so far and the new prompt. Nothing
+// here reads settings or input, so the same arguments always give the same messages
+fn build_messages(system_prompt: &str, history: &[Message], prompt: &str) -> Vec<Message> {
+    let mut messages = vec![Message { role: "system".to_string(), content: system_prompt.to_string() }];
+    messages.extend(history.iter().cloned());
+    messages.push(Message { role: "user".to_string(), content: prompt.to_string() });
+    messages
+}
+
+// Sends a conversation to the API, first asking for confirmation when the request looks expensive
+fn send_messages(client: &dyn ApiClient, session: &Session, messages: Vec<Message>, settings: &GenerationSettings) -> Result<Option<Reply>, AssistantError> {
+    // Catches a Ctrl-C pressed at any prompt the mode showed before sending
+    check_cancelled()?;
+    if let Some(golden) = &session.golden {
+        golden.requests.borrow_mut().push(messages);
+        return Ok(None);
+    }
+    // A rough upper bound: every completion token the request allows is counted as if it were used
+    let prompt_tokens = estimate_prompt_tokens(&messages);
+    let response_tokens = (settings.max_tokens * settings.choices) as usize;
+    // Without anyone to ask, the request is sent, as a script answering with Enter would
+    if needs_cost_confirmation(prompt_tokens + response_tokens, session.cost_preview.threshold)
+        && session.interactive && !confirm_cost(prompt_tokens, response_tokens, session.cost_preview.price_per_1k) {
+        say!("Request cancelled.");
+        return Ok(None);
+    }
+
+    let mut reply = request_reply(client, request_payload(messages.clone(), settings, session.stream))?;
+    report_usage(session, reply.usage);
+    report_latency(session, reply.elapsed);
+    if !reply.alternatives.is_empty() {
+        reply = choose_reply(reply, session.interactive && session.json.is_none());
+    }
+    // A reply cut off at the token limit can be picked up where it stopped and joined onto the first part
+    while reply.truncated {
+        say!("(response truncated — increase MAX_TOKENS to see the rest)");
+        // `--yes` always asks for the rest; without it, only someone at a terminal is asked
+        let continue_reply = session.assume_yes || (session.interactive && ask_yes_no("Request the rest of the response?"));
+        if !continue_reply {
+            break;
+        }
+        let mut follow_up = messages.clone();
+        follow_up.push(Message { role: "assistant".to_string(), content: reply.text.clone() });
+        follow_up.push(Message { role: "user".to_string(), content: CONTINUE_PROMPT.to_string() });
+        let rest = request_reply(client, RequestPayload { continuation: true, ..request_payload(follow_up, settings, session.stream) })?;
+        report_usage(session, rest.usage);
+        report_latency(session, rest.elapsed);
+        let elapsed = reply.elapsed.zip(rest.elapsed).map(|(first, rest)| first + rest);
+        reply = Reply { text: join_continuation(&reply.text, &rest.text), alternatives: Vec::new(), elapsed, ..rest };
+    }
+    Ok(Some(reply))
+}
+
+// Shows every choice, numbered, and keeps the one picked, which is what gets cached and saved.
+// Without a terminal to ask on, or with `--json`, the first choice is kept as if only one was asked for
+fn choose_reply(reply: Reply, interactive: bool) -> Reply {
+    let mut choices = vec![(reply.text.clone(), reply.truncated)];
+    choices.extend(reply.alternatives.iter().cloned());
+    if !interactive {
+        return Reply { alternatives: Vec::new(), ..reply };
+    }
+    for (number, (text, _)) in choices.iter().enumerate() {
+        say!("--- Choice {} of {} ---", number + 1, choices.len());
+        say!("{}", text);
+    }
+    let answer = prompt_line(&format!("Keep which choice? (1-{}, Enter for 1): ", choices.len()));
+    let index = match answer.parse::<usize>() {
+        Ok(number) if (1..=choices.len()).contains(&number) => number - 1,
+        _ if answer.is_empty() => 0,
+        _ => {
+            say!("'{}' is not a choice, keeping the first one.", answer);
+            0
+        }
+    };
+    let (text, truncated) = choices.swap_remove(index);
+    Reply { text, truncated, alternatives: Vec::new(), ..reply }
+}
+
+fn request_reply(client: &dyn ApiClient, request_payload: RequestPayload) -> Result<Reply, AssistantError> {
+    // A streamed response shows its own progress as it arrives
+    let _spinner = if request_payload.stream { None } else { Spinner::start("Thinking…") };
+    let started = Instant::now();
+    let reply = client.send(&request_payload)?;
+    Ok(Reply { elapsed: Some(started.elapsed()), ..reply })
+}
+
+fn request_payload(messages: Vec<Message>, settings: &GenerationSettings, stream: bool) -> RequestPayload {
+    RequestPayload {
+        model: settings.model.clone(),
+        messages,
+        temperature: settings.temperature,
+        top_p: settings.top_p,
+        max_tokens: settings.max_tokens,
+        n: (settings.choices > 1).then_some(settings.choices),
+        // The choices would arrive interleaved, so they are only compared once complete
+        stream: stream && settings.choices == 1,
+        continuation: false,
+    }
+}
+
+// An animation on stderr while a request is in flight; it stops and clears its line when dropped
+struct Spinner {
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Spinner {
+    // None unless stderr is a terminal, so redirected output never gets the animation's control characters
+    fn start(message: &'static str) -> Option<Spinner> {
+        if !io::stderr().is_terminal() {
+            return None;
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            for frame in ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'].iter().cycle() {
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                eprint!("\r{} {}", frame, message);
+                let _ = io::stderr().flush();
+                thread::sleep(Duration::from_millis(80));
+            }
+            eprint!("\r\x1b[K");
+            let _ = io::stderr().flush();
+        });
+        Some(Spinner { stop, thread: Some(thread) })
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Prints how long a request took and keeps it for the batch summary
+fn report_latency(session: &Session, elapsed: Option<Duration>) {
+    let Some(elapsed) = elapsed else {
+        return;
+    };
+    session.latencies.borrow_mut().push(elapsed);
+    info!("Took {}.", format_duration(elapsed));
+}
+
+// Seconds with one decimal, or milliseconds below a second, where a decimal would round to 0.0s
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+// Min, median, p95 and max of the requests a batch sent, using the nearest-rank method
+fn latency_summary(latencies: &mut [Duration]) -> Option<String> {
+    if latencies.is_empty() {
+        return None;
+    }
+    latencies.sort();
+    let rank = |percentile: usize| latencies[(latencies.len() * percentile).div_ceil(100).max(1) - 1];
+    Some(format!(
+        "min {}, median {}, p95 {}, max {} over {} requests",
+        format_duration(latencies[0]), format_duration(rank(50)), format_duration(rank(95)),
+        format_duration(latencies[latencies.len() - 1]), latencies.len()
+    ))
+}
+
+// Prints what a request consumed and adds it to the session total, quoting costs when PRICE_PER_1K_TOKENS is set
+fn report_usage(session: &Session, usage: Option<Usage>) {
+    let Some(usage) = usage else {
+        return;
+    };
+    let session_tokens = session.tokens_used.get() + usage.total_tokens;
+    session.tokens_used.set(session_tokens);
+    match session.cost_preview.price_per_1k {
+        Some(price) => say!(
+            "Tokens: {} prompt + {} completion = {} (~${:.4}; session total {} tokens, ~${:.4}).",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens,
+            usage.total_tokens as f64 / 1000.0 * price, session_tokens, session_tokens as f64 / 1000.0 * price
+        ),
+        None => say!(
+            "Tokens: {} prompt + {} completion = {} (session total {}).",
+            usage.prompt_tokens, usage.completion_tokens, usage.total_tokens, session_tokens
+        ),
+    }
+}
+
+// ~4 characters per token, which is close enough for English text and code
+fn estimate_prompt_tokens(messages: &[Message]) -> usize {
+    let prompt_chars: usize = messages.iter().map(|message| message.content.chars().count()).sum();
+    prompt_chars.div_ceil(4)
+}
+
+fn needs_cost_confirmation(estimated_tokens: usize, threshold: usize) -> bool {
+    estimated_tokens > threshold
+}
+
+// Splits the estimate so an oversized prompt, such as a whole file pasted by mistake, stands out
+fn confirm_cost(prompt_tokens: usize, response_tokens: usize, price_per_1k: Option<f64>) -> bool {
+    let estimated_tokens = prompt_tokens + response_tokens;
+    let breakdown = format!("~{} for the prompt, up to {} for the response", prompt_tokens, response_tokens);
+    match price_per_1k {
+        Some(price) => ask!("This request is ~{} tokens ({}), ~${:.4}. Proceed? [Y/n]: ", estimated_tokens, breakdown, estimated_tokens as f64 / 1000.0 * price),
+        None => ask!("This request is ~{} tokens ({}). Proceed? [Y/n]: ", estimated_tokens, breakdown),
+    }
+
+    // Input that ends before an answer, or can't be read, sends nothing rather than spending tokens unasked
+    read_answer().is_some_and(|answer| !matches!(answer.trim().to_ascii_lowercase().as_str(), "n" | "no"))
+}
+
+fn view_settings(config: &Config) {
+    say!("Effective settings:");
+    for setting in &config.settings {
+        let value = if SECRET_SETTINGS.contains(&setting.name.as_str()) {
+            "********".to_string()
+        } else if matches!(setting.name.as_str(), "HTTPS_PROXY" | "HTTP_PROXY") {
+            without_credentials(&setting.value) // A proxy URL may carry a password
+        } else {
+            setting.value.clone()
+        };
+        let source = match setting.source {
+            SettingSource::Default => "default",
+            SettingSource::ConfigFile => "config.toml",
+            SettingSource::EnvFile => ".env file",
+            SettingSource::Environment => "environment",
+            SettingSource::Given => "command line",
+        };
+        say!("  {} = {} ({})", setting.name, value, source);
+    }
+}
+
+fn clear_cache(cache: &mut Cache, assume_yes: bool) {
+    let count = cache.entries.len();
+    if count == 0 {
+        say!("The cache is already empty.");
+        return;
+    }
+    if !confirm(&format!("This will remove {} entries. Continue?", count), assume_yes) {
+        say!("Cache left unchanged.");
+        return;
+    }
+    cache.clear();
+    say!("Removed {} cached entries.", count);
+}
+
+fn list_cache(cache: &Cache) {
+    if cache.entries.is_empty() {
+        say!("The cache is empty.");
+        return;
+    }
+    let now = now_secs();
+    for (position, entry) in cache.entries.iter().enumerate() {
+        say!("{:>3}. [{}] {}", position + 1, cached_age(now, entry.created_at), cache_preview(entry));
+    }
+}
+
+// Lists the cache and prints the response picked by number in full, as it was shown when it arrived,
+// so earlier answers can be read again without sending anything
+fn show_cached_response(session: &Session) {
+    let cache = session.cache();
+    if cache.entries.is_empty() {
+        say!("The cache is empty.");
+        return;
+    }
+    list_cache(&cache);
+    let answer = prompt_line("Entry to show (Enter to cancel): ");
+    if answer.is_empty() {
+        return;
+    }
+    let response = match answer.parse::<usize>() {
+        Ok(number) if (1..=cache.entries.len()).contains(&number) => cache.entries[number - 1].response.clone(),
+        _ => {
+            say!("No entry {}.", answer);
+            return;
+        }
+    };
+    // Paging waits for Enter, which must not hold the cache lock
+    drop(cache);
+    let reply = Reply { text: response, streamed: false, truncated: false, usage: None, cached: true, prompt_hash: None, alternatives: Vec::new(), elapsed: None };
+    print_reply(&reply, session);
+}
+
+fn remove_cache_entry(cache: &mut Cache, assume_yes: bool) {
+    if cache.entries.is_empty() {
+        say!("The cache is empty.");
+        return;
+    }
+    list_cache(cache);
+    ask!("Entry to remove (Enter to cancel): ");
+    let answer = read_answer().unwrap_or_default();
+    if answer.trim().is_empty() {
+        say!("Cache left unchanged.");
+        return;
+    }
+    match answer.trim().parse::<usize>() {
+        Ok(number) if (1..=cache.entries.len()).contains(&number) => {
+            if !confirm(&format!("Remove entry {}: {}?", number, cache_preview(&cache.entries[number - 1])), assume_yes) {
+                say!("Cache left unchanged.");
+                return;
+            }
+            let entry = cache.remove(number - 1);
+            say!("Removed entry {}: {}", number, cache_preview(&entry));
+        }
+        _ => say!("No entry {}; cache left unchanged.", answer.trim()),
+    }
+}
+
+// The stored prompt on one line, without the language sentence every prompt starts with, shortened to fit
+fn cache_preview(entry: &CacheEntry) -> String {
+    let prompt = entry.prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+    if prompt.is_empty() {
+        return "(prompt not stored)".to_string();
+    }
+    let prompt = match prompt.split_once(" code. ") {
+        Some((intro, rest)) if intro.starts_with("You are working with") => rest,
+        _ => prompt.as_str(),
+    };
+    one_line_preview(prompt, 70)
+}
+
+// Text on a single line, cut to `max_chars`
+fn one_line_preview(text: &str, max_chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() > max_chars {
+        format!("{}...", text.chars().take(max_chars).collect::<String>())
+    } else {
+        text
+    }
+}
+
+// How long ago an entry was cached, from its UNIX timestamp; entries from older versions have none
+fn cached_age(now: u64, created_at: u64) -> String {
+    if created_at == 0 {
+        return "age unknown".to_string();
+    }
+    let age = now.saturating_sub(created_at);
+    match age {
+        0..=59 => format!("{}s ago", age),
+        60..=3599 => format!("{}m ago", age / 60),
+        3600..=86399 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    }
+}
+
+fn clear_conversation(conversation: &mut Conversation) {
+    conversation.clear();
+    say!("Conversation cleared; the next request starts fresh.");
+}
+
+// Asks a yes/no question before a destructive action; anything but "y"/"yes" counts as no
+fn confirm(prompt: &str, assume_yes: bool) -> bool {
+    let answer = if assume_yes {
+        String::new()
+    } else {
+        ask!("{} [y/N]: ", prompt);
+        read_answer().unwrap_or_default()
+    };
+    confirm_answer(&answer, assume_yes)
+}
+
+// With `--yes` nothing was asked, so there is no answer to look at
+fn confirm_answer(answer: &str, assume_yes: bool) -> bool {
+    assume_yes || is_yes(answer)
+}
+
+// Asks for a single line of text, returned trimmed; empty once input has ended
+fn prompt_line(question: &str) -> String {
+    ask!("{}", question);
+    read_answer().unwrap_or_default().trim().to_string()
+}
+
+// Asks a yes/no question where declining is the default
+fn ask_yes_no(question: &str) -> bool {
+    ask!("{} (y/n): ", question);
+    read_answer().is_some_and(|answer| is_yes(&answer))
+}
+
+// A line from stdin, or None once input has ended or can't be read
+fn read_answer() -> Option<String> {
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(answer),
+    }
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+// Reads the code to work on from `code_file` when one was given, otherwise asks where to get it
+// None when there is no code to send, so the caller returns to the menu without making a request
+fn get_code_input(code_file: Option<&str>, session: &Session) -> Result<Option<CodeInput>, AssistantError> {
+    match read_code_input(code_file, session)? {
+        Some(input) if !input.text.trim().is_empty() => Ok(Some(input)),
+        _ => {
+            warn!("No code provided.");
+            Ok(None)
+        }
+    }
+}
+
+// None when input ends before any code is given, so a scripted run can't loop on the prompts forever
+fn read_code_input(code_file: Option<&str>, session: &Session) -> Result<Option<CodeInput>, AssistantError> {
+    if let Some(path) = code_file {
+        return Ok(Some(CodeInput::from_file(path, read_text_file(path, session)?)));
+    }
+    say!("Would you like to input the code manually or read it from a file?");
+    say!("1. Manual Input");
+    say!("2. Read from a file (default '{}')", CODE_INPUT_FILE);
+    ask!("Choose an option: ");
+
+    let Some(choice) = read_answer() else {
+        return Ok(None);
+    };
+
+    check_cancelled()?;
+    match choice.trim() {
+        "1" => {
+            say!("Enter your code (type '{}' on a new line when finished):", end_marker(&session.config));
+            let code = read_multiline_input(&session.config);
+            check_cancelled()?;
+            Ok(Some(CodeInput { text: code, extension_language: None }))
+        },
+        "2" => loop {
+            ask!("File path (press Enter for '{}'): ", CODE_INPUT_FILE);
+            let Some(path) = read_answer() else {
+                return Ok(None);
+            };
+            check_cancelled()?;
+            let path = match path.trim() {
+                "" => CODE_INPUT_FILE.to_string(),
+                path => path.to_string(),
+            };
+            match read_text_file(&path, session) {
+                Ok(content) => return Ok(Some(CodeInput::from_file(&path, content))),
+                Err(error) => say!("Could not read '{}' ({}). Please try again.", path, error),
+            }
+        },
+        _ => {
+            say!("Invalid option, please try again.");
+            read_code_input(code_file, session)
+        }
+    }
+}
+
+// Code to work on, with the language its file extension names when it was read from a file
+struct CodeInput {
+    text: String,
+    extension_language: Option<&'static str>,
+}
+
+impl CodeInput {
+    fn from_file(path: &str, content: String) -> CodeInput {
+        CodeInput { text: content, extension_language: language_from_extension(path) }
+    }
+}
+
+// An extension is far more reliable than the content heuristics, so it is used whenever it is known
+fn language_from_extension(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "rs" => Some("Rust"),
+        "py" => Some("Python"),
+        "js" | "mjs" | "cjs" | "jsx" => Some("JavaScript"),
+        "cpp" | "cc" | "cxx" | "hpp" => Some("C++"),
+        "c" => Some("C"),
+        "java" => Some("Java"),
+        "go" => Some("Go"),
+        "ts" | "tsx" => Some("TypeScript"),
+        "html" | "htm" => Some("HTML"),
+        "sql" => Some("SQL"),
+        "cs" => Some("C#"),
+        "rb" => Some("Ruby"),
+        _ => None,
+    }
+}
+
+// Reads a file of code as text. UTF-16 files, as some Windows editors save them, are recognized by their
+// byte order mark and decoded. Anything else that isn't UTF-8, such as a Latin-1 file, is decoded with the
+// invalid bytes replaced by U+FFFD; when `interactive`, the user is asked first and can skip the file instead
+fn read_text_file(path: &str, session: &Session) -> Result<String, AssistantError> {
+    // Checked before reading, so pointing at a huge file doesn't load it all into memory first
+    let max_bytes = session.config.setting("MAX_INPUT_BYTES", DEFAULT_MAX_INPUT_BYTES);
+    let size = fs::metadata(path)?.len();
+    if size > max_bytes {
+        return Err(AssistantError::Input(format!(
+            "'{}' is {} bytes, over the MAX_INPUT_BYTES limit of {}. Split it into smaller files, e.g. one for each module, \
+             or raise MAX_INPUT_BYTES; code that doesn't fit CONTEXT_LIMIT is then sent in parts",
+            path, size, max_bytes
+        )));
+    }
+    let bytes = fs::read(path)?;
+    let utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = bytes.chunks(2).map(|pair| from_bytes([pair[0], *pair.get(1).unwrap_or(&0)])).collect();
+        String::from_utf16_lossy(&units)
+    };
+    if let Some(rest) = bytes.strip_prefix(&[0xff, 0xfe]) {
+        info!("Notice: '{}' is UTF-16 (little-endian); it was converted to UTF-8.", path);
+        return Ok(utf16(rest, u16::from_le_bytes));
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xfe, 0xff]) {
+        info!("Notice: '{}' is UTF-16 (big-endian); it was converted to UTF-8.", path);
+        return Ok(utf16(rest, u16::from_be_bytes));
+    }
+    // Text never contains NUL bytes outside UTF-16, which was handled above
+    if bytes.contains(&0) {
+        return Err(AssistantError::Input(format!("'{}' looks like a binary file, not code", path)));
+    }
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(strip_bom(content)),
+        Err(error) => {
+            let position = error.utf8_error().valid_up_to();
+            warn!("'{}' is not valid UTF-8 (the first invalid byte is at offset {}); it may be in another encoding.", path, position);
+            if session.interactive && !ask_yes_no("Use it anyway, with the invalid bytes replaced by \u{fffd}?") {
+                return Err(AssistantError::Parse(format!("'{}' was skipped, since it is not valid UTF-8.", path)));
+            }
+            Ok(strip_bom(String::from_utf8_lossy(error.as_bytes()).into_owned()))
+        }
+    }
+}
+
+// Files saved on Windows often start with a UTF-8 byte order mark, which would otherwise
+// end up in the prompt and hide markers like `#include` from language detection
+fn strip_bom(content: String) -> String {
+    match content.strip_prefix('\u{feff}') {
+        Some(stripped) => stripped.to_string(),
+        None => content,
+    }
+}
+
+/// Everything that can go wrong, so callers and the retry logic can tell the failures apart.
+#[derive(Debug, Error)]
+pub enum AssistantError {
+    /// The endpoint could not be reached, or the connection broke.
+    #[error("{}", transport_message(.0))]
+    Network(Box<ureq::Transport>),
+    /// The endpoint answered with an error status.
+    #[error("the API returned HTTP status {status}")]
+    Http { status: u16 },
+    /// A response or file was not in the expected shape.
+    #[error("{0}")]
+    Parse(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A setting, argument or prompts file is missing or invalid.
+    #[error("{0}")]
+    Config(String),
+    /// The API or local model answered, but without a usable response.
+    #[error("{message}")]
+    Api { message: String },
+    /// The answer was empty or only whitespace, even after retrying (EMPTY_RESPONSE_RETRIES).
+    #[error("no response generated: the answer was empty")]
+    EmptyResponse,
+    /// Something typed at a prompt could not be used.
+    #[error("{0}")]
+    Input(String),
+    /// Some files of a batch failed; each was reported as it happened.
+    #[error("{failed} of {total} files failed")]
+    BatchFailed { failed: u32, total: usize },
+    /// Ctrl-C was pressed, so the action unwinds back to the main menu.
+    #[error("cancelled")]
+    Cancelled,
+    /// The endpoint did not connect or answer within the configured timeout.
+    #[error("request timed out after {secs}s")]
+    TimedOut { secs: u64 },
+    /// A `--golden` run built prompts that differ from the file, starting at `line`.
+    #[error("the prompts no longer match {path} (first difference at line {line}); pass --force to accept them")]
+    PromptChanged { path: String, line: usize },
+    /// A `--mode` run ended without a response, e.g. because no code was given or a language check was declined.
+    #[error("the mode ended without a response")]
+    NoAnswer,
+    /// Like `Network`, from the async backend.
+    #[cfg(feature = "async")]
+    #[error("{}", error_chain(.0))]
+    AsyncNetwork(reqwest::Error),
+}
+
+// reqwest's own message is only the outermost layer ("error sending request"); the cause is in its sources
+#[cfg(feature = "async")]
+fn error_chain(error: &reqwest::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        message.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    message
+}
+
+// The URL is dropped, since with AUTH_STYLE=query it carries the key
+#[cfg(feature = "async")]
+impl From<reqwest::Error> for AssistantError {
+    fn from(error: reqwest::Error) -> AssistantError {
+        AssistantError::AsyncNetwork(error.without_url())
+    }
+}
+
+// ureq's message starts with the URL, which carries the key with AUTH_STYLE=query, so the query string is left out
+fn transport_message(transport: &ureq::Transport) -> String {
+    let message = transport.to_string();
+    match transport.url().filter(|url| url.query().is_some()) {
+        Some(url) => {
+            let mut bare = url.clone();
+            bare.set_query(None);
+            message.replacen(url.as_str(), bare.as_str(), 1)
+        }
+        None => message,
+    }
+}
+
+impl From<ureq::Error> for AssistantError {
+    fn from(error: ureq::Error) -> AssistantError {
+        match error {
+            ureq::Error::Status(status, _) => AssistantError::Http { status },
+            ureq::Error::Transport(transport) => AssistantError::Network(Box::new(transport)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AssistantError {
+    fn from(error: serde_json::Error) -> AssistantError {
+        AssistantError::Parse(error.to_string())
+    }
+}
+
+impl AssistantError {
+    /// A stable name for the kind of failure, as reported in `--json` output: `network`, `auth` (HTTP 401
+    /// or 403), `http`, `parse`, `io`, `config`, `api`, `empty_response`, `input`, `batch_failed`, `cancelled`,
+    /// `timed_out` or `prompt_changed`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AssistantError::Network(_) => "network",
+            #[cfg(feature = "async")]
+            AssistantError::AsyncNetwork(_) => "network",
+            AssistantError::Http { status: 401 | 403 } => "auth",
+            AssistantError::Http { .. } => "http",
+            AssistantError::Parse(_) => "parse",
+            AssistantError::Io(_) => "io",
+            AssistantError::Config(_) => "config",
+            AssistantError::Api { .. } => "api",
+            AssistantError::EmptyResponse => "empty_response",
+            AssistantError::Input(_) => "input",
+            AssistantError::BatchFailed { .. } => "batch_failed",
+            AssistantError::Cancelled => "cancelled",
+            AssistantError::TimedOut { .. } => "timed_out",
+            AssistantError::PromptChanged { .. } => "prompt_changed",
+            AssistantError::NoAnswer => "no_answer",
+        }
+    }
+
+    /// The exit status `--mode` and startup failures end the process with.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AssistantError::Cancelled => EXIT_CANCELLED,
+            AssistantError::TimedOut { .. } => EXIT_TIMED_OUT,
+            AssistantError::Config(_) => EXIT_USAGE,
+            AssistantError::NoAnswer => EXIT_NO_ANSWER,
+            _ => EXIT_FAILURE,
+        }
+    }
+
+    // Rate limiting, server errors and connection problems are worth retrying; other failures are not.
+    // Timeouts aren't retried, since each attempt would leave the tool hanging for the whole timeout again
+    fn is_transient(&self) -> bool {
+        match self {
+            AssistantError::Http { status } => matches!(status, 429 | 500 | 502 | 503 | 504),
+            AssistantError::Network(_) => self.timeout_phase().is_none(),
+            #[cfg(feature = "async")]
+            AssistantError::AsyncNetwork(_) => self.timeout_phase().is_none(),
+            _ => false,
+        }
+    }
+
+    // An HTTP error status that retrying would not fix
+    fn is_permanent(&self) -> bool {
+        matches!(self, AssistantError::Http { .. }) && !self.is_transient()
+    }
+
+    // Whether this is a timeout, from ureq while sending or from reading the response body afterwards
+    fn timeout_phase(&self) -> Option<TimeoutPhase> {
+        match self {
+            AssistantError::Network(transport) => {
+                let source = std::error::Error::source(transport.as_ref())?;
+                let timed_out = source.downcast_ref::<io::Error>().is_some_and(|error| error.kind() == io::ErrorKind::TimedOut);
+                if !timed_out {
+                    None
+                } else if transport.kind() == ureq::ErrorKind::ConnectionFailed {
+                    Some(TimeoutPhase::Connect)
+                } else {
+                    Some(TimeoutPhase::Read)
+                }
+            }
+            AssistantError::Io(error) => (error.kind() == io::ErrorKind::TimedOut).then_some(TimeoutPhase::Read),
+            #[cfg(feature = "async")]
+            AssistantError::AsyncNetwork(error) if error.is_timeout() => {
+                Some(if error.is_connect() { TimeoutPhase::Connect } else { TimeoutPhase::Read })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn check_cancelled() -> Result<(), AssistantError> {
+    if INTERRUPTED.load(Ordering::Relaxed) {
+        Err(AssistantError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+// Reads lines from stdin until a line that is only the end marker (surrounding whitespace aside) or end of input.
+// Every other line is kept exactly as typed, tabs and trailing spaces included, since indentation can matter
+fn read_multiline_input(config: &Config) -> String {
+    let marker = end_marker(config);
+    let mut text = String::new();
+    loop {
+        let mut line = String::new();
+        let read = io::stdin().read_line(&mut line);
+        if matches!(read, Ok(0) | Err(_)) || line.trim() == marker || INTERRUPTED.load(Ordering::Relaxed) {
+            break;
+        }
+        text.push_str(&line);
+    }
+    text
+}
+
+// END_MARKER ends pasted input, for code with a line that is just `END`
+fn end_marker(config: &Config) -> String {
+    config.value("END_MARKER")
+        .map(|marker| marker.trim().to_string())
+        .filter(|marker| !marker.is_empty())
+        .unwrap_or_else(|| "END".to_string())
+}
+
+// Accepts a snippet when the specified language is the most likely one with enough confidence;
+// otherwise the user decides, since the detector can't recognize every valid fragment. Without anyone to
+// ask, as in a batch, a mismatch is declined
+fn check_language(input: &CodeInput, specified_language: &str, session: &Session) -> bool {
+    let interactive = session.interactive;
+    if session.config.flag("SKIP_LANGUAGE_CHECK") {
+        return true;
+    }
+    if let Some(language) = input.extension_language {
+        return language.eq_ignore_ascii_case(specified_language)
+            || interactive && ask_yes_no(&format!("The file extension says {} but you specified {} — continue anyway?", language, specified_language));
+    }
+    let detected = extract_language_from_code(&input.text);
+    if detected.name.eq_ignore_ascii_case(specified_language) && detected.confidence >= LANGUAGE_CONFIDENCE_THRESHOLD {
+        return true;
+    }
+    interactive && ask_yes_no(&format!("Detected {} but you specified {} — continue anyway?", detected.name, specified_language))
+}
+
+// Scores every language's signals and returns the best match; confidence is its share of all points scored
+fn extract_language_from_code(code_content: &str) -> DetectedLanguage {
+    let scores: Vec<(&str, u32)> = LANGUAGE_SIGNALS.iter()
+        .map(|&(language, signals)| {
+            let score: u32 = signals.iter()
+                // Repeats count, but only up to a point, so one common token can't outweigh everything else
+                .map(|&(pattern, weight)| code_content.matches(pattern).count().min(3) as u32 * weight)
+                .sum();
+            (language, score + line_signals(language, code_content))
+        })
+        .collect();
+    let total: u32 = scores.iter().map(|&(_, score)| score).sum();
+    match scores.into_iter().max_by_key(|&(_, score)| score) {
+        Some((language, score)) if score > 0 => DetectedLanguage {
+            name: language.to_string(),
+            confidence: score as f64 / total as f64,
+        },
+        _ => DetectedLanguage { name: "Unknown".to_string(), confidence: 0.0 },
+    }
+}
+
+// Evidence that plain substrings can't express: how a line starts and ends. This is what separates a Python
+// `def` from a Ruby one, and real definitions from `function` or `def` mentioned in a comment or string
+fn line_signals(language: &str, code_content: &str) -> u32 {
+    let mut score = 0;
+    for line in code_content.lines().map(str::trim) {
+        let weight = match language {
+            "Python" if line.starts_with("def ") && line.ends_with(':') => 6,
+            "Python" if line.starts_with("class ") && line.ends_with(':') => 5,
+            "Ruby" if line.starts_with("def ") && !line.ends_with(':') => 4,
+            "Ruby" if line == "end" => 2,
+            "JavaScript" if line.starts_with("function ") || line.starts_with("async function ") => 4,
+            "JavaScript" if line.contains("= function") => 4,
+            "Java" if is_java_class_declaration(line) => 5,
+            _ => 0,
+        };
+        score += weight;
+    }
+    // A handful of matching lines is enough; a long file shouldn't drown out the other signals
+    score.min(18)
+}
+
+// `class Name {` or `public final class Name extends Base {`, as opposed to C++'s `class Name;` or Python's `class Name:`
+fn is_java_class_declaration(line: &str) -> bool {
+    let declaration = ["public ", "private ", "protected ", "abstract ", "final ", "static "].iter()
+        .fold(line, |rest, modifier| rest.strip_prefix(modifier).unwrap_or(rest));
+    let Some(rest) = declaration.strip_prefix("class ").or_else(|| declaration.strip_prefix("interface ")) else {
+        return false;
+    };
+    let name = rest.split(|c: char| !c.is_alphanumeric() && c != '_').next().unwrap_or("");
+    name.starts_with(|c: char| c.is_ascii_uppercase()) && rest.ends_with('{') && !rest.contains(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::{BufRead, BufReader};
+    use std::sync::Mutex;
+    use std::thread;
+
+    // A path of its own in the temp directory for each test, with nothing left there from an earlier run
+    fn scratch_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("final_project-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    // The built-in templates, as used when no prompts file exists
+    fn default_prompts() -> PromptTemplates {
+        PromptTemplates::load("no-such-prompts-file.json").unwrap()
+    }
+
+    fn test_cache() -> Cache {
+        Cache { limit: DEFAULT_CACHE_LIMIT, ..Cache::default() }
+    }
+
+    // Answers with its canned replies in order and keeps the messages of every request it was sent
+    struct MockClient {
+        replies: Mutex<Vec<Reply>>,
+        sent: Mutex<Vec<Vec<Message>>>,
+    }
+
+    impl MockClient {
+        fn new(replies: Vec<Reply>) -> MockClient {
+            MockClient { replies: Mutex::new(replies), sent: Mutex::new(Vec::new()) }
+        }
+
+        fn sent(&self) -> Vec<Vec<Message>> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl ApiClient for MockClient {
+        fn send(&self, payload: &RequestPayload) -> Result<Reply, AssistantError> {
+            self.sent.lock().unwrap().push(payload.messages.clone());
+            let mut replies = self.replies.lock().unwrap();
+            if replies.is_empty() {
+                return Err(AssistantError::Api { message: "the mock has no replies left".to_string() });
+            }
+            Ok(replies.remove(0))
+        }
+    }
+
+    fn reply(text: &str) -> Reply {
+        Reply { text: text.to_string(), streamed: false, truncated: false, usage: None, cached: false, prompt_hash: None, alternatives: Vec::new(), elapsed: None }
+    }
+
+    // A session as a script would have one: no terminal to ask, nothing streamed, colored or written out
+    fn test_session() -> Session {
+        Session {
+            cache: Arc::new(Mutex::new(test_cache())),
+            conversation: Conversation::new(20),
+            code_file: None,
+            focus_lines: None,
+            output_file: None,
+            code_only: false,
+            highlighter: None,
+            cost_preview: CostPreview { threshold: usize::MAX, price_per_1k: None },
+            stream: false,
+            tokens_used: Cell::new(0),
+            cache_hits: 0,
+            prompts: default_prompts(),
+            json: None,
+            review_prompt: false,
+            answered: 0,
+            dry_run: false,
+            interactive: false,
+            in_flight: Arc::new(InFlight::default()),
+            last_action: None,
+            history_file: None,
+            clipboard: None,
+            review_passes: 0,
+            latencies: RefCell::new(Vec::new()),
+            cache_enabled: true,
+            golden: None,
+            assume_yes: false,
+            config: Config::default(),
+        }
+    }
+
+    // Settings as if they were set in the environment
+    fn test_config(settings: &[(&str, &str)]) -> Config {
+        let environment = settings.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect();
+        Config::from_sources(&environment, &HashMap::new(), &HashMap::new())
+    }
+
+    #[test]
+    fn fetch_sends_the_system_prompt_and_the_mode_prompt() {
+        let client = MockClient::new(vec![reply("It adds one.")]);
+        let mut session = test_session();
+        let prompt = "You are working with Rust code. Your task is to explain the following code:\n\nfn inc(x: i32) -> i32 { x + 1 }".to_string();
+        let request = ModeRequest::new(&Config::default(), "explanation", "Rust", prompt.clone(), 500);
+        let system_prompt = request.system_prompt.text.clone();
+
+        let answer = fetch(&client, &mut session, request).unwrap().unwrap();
+
+        assert_eq!(answer.text, "It adds one.");
+        let sent = client.sent();
+        assert_eq!(sent.len(), 1);
+        let roles: Vec<&str> = sent[0].iter().map(|message| message.role.as_str()).collect();
+        assert_eq!(roles, ["system", "user"]);
+        assert_eq!(sent[0][0].content, system_prompt);
+        assert_eq!(sent[0][1].content, prompt);
+    }
+
+    #[test]
+    fn a_cached_answer_is_used_without_calling_the_client() {
+        let client = MockClient::new(Vec::new());
+        let mut session = test_session();
+        let request = ModeRequest::prefixed(&Config::default(), "explanation", "Rust", "Explain this".to_string(), 500);
+        session.cache().add_entry(request.full_cache_key(), "From the cache.".to_string());
+
+        let answer = fetch(&client, &mut session, request).unwrap().unwrap();
+
+        assert_eq!(answer.text, "From the cache.");
+        assert!(client.sent().is_empty());
+    }
+
+    #[test]
+    fn confirmation_needs_a_yes_unless_assumed() {
+        assert!(confirm_answer("y\n", false));
+        assert!(confirm_answer("Yes", false));
+        assert!(!confirm_answer("n\n", false));
+        assert!(!confirm_answer("", false));
+        assert!(confirm_answer("", true));
+        assert!(confirm_answer("n", true));
+    }
+
+    #[test]
+    fn the_warnings_prompt_includes_the_code_only_when_given() {
+        let warnings = "warning: unused variable: `x`";
+
+        let without_code = build_warnings_prompt(&default_prompts(), "Rust", warnings, None);
+        assert!(without_code.contains(warnings));
+        assert!(!without_code.contains("The warnings were produced by this code"));
+
+        let with_code = build_warnings_prompt(&default_prompts(), "Rust", warnings, Some("let x = 1;"));
+        assert!(with_code.starts_with(&without_code));
+        assert!(with_code.ends_with("The warnings were produced by this code:\n\nlet x = 1;"));
+    }
+
+    #[test]
+    fn a_compressed_cache_survives_saving_and_appending() {
+        let path = scratch_path("cache.json.gz");
+        let mut cache = test_cache();
+        cache.add_entry("first prompt".to_string(), "first answer".to_string());
+        save_cache(&path, &mut cache).unwrap();
+        let first_save = fs::read(&path).unwrap();
+        assert!(first_save.starts_with(&GZIP_MAGIC));
+
+        cache.add_entry("second prompt".to_string(), "second answer".to_string());
+        save_cache(&path, &mut cache).unwrap();
+        // The second save added a gzip member after the first instead of rewriting the file
+        let both_saves = fs::read(&path).unwrap();
+        assert!(both_saves.len() > first_save.len());
+        assert!(both_saves.starts_with(&first_save));
+        assert!(both_saves[first_save.len()..].starts_with(&GZIP_MAGIC));
+
+        let mut loaded = parse_cache(&read_cache_file(&path).unwrap()).unwrap();
+        loaded.rebuild_index();
+        assert_eq!(loaded.lookup("first prompt").map(|entry| entry.response.as_str()), Some("first answer"));
+        assert_eq!(loaded.lookup("second prompt").map(|entry| entry.response.as_str()), Some("second answer"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_property_tests_prompt_names_a_framework_for_the_language() {
+        let prompt = build_property_tests_prompt(&default_prompts(), "Python", "def add(a, b): return a + b");
+        assert!(prompt.contains("using Hypothesis"));
+        assert!(prompt.ends_with("def add(a, b): return a + b"));
+
+        // Matched case-insensitively, with a generic fallback for the rest
+        assert_eq!(property_test_framework("RUST"), "proptest");
+        assert!(build_property_tests_prompt(&default_prompts(), "Go", "").contains("the language's most common property-based testing library"));
+    }
+
+    #[test]
+    fn an_overridden_system_prompt_is_part_of_the_cache_key() {
+        let key = |config: &Config| ModeRequest::prefixed(config, "keytest", "Rust", "Explain".to_string(), 100).full_cache_key();
+        let config = Config::default();
+        assert_eq!(system_prompt_for(&config, "keytest", "Rust").text, "You are an expert Rust software engineer.");
+        let base_key = key(&config);
+        assert_eq!(base_key, "keytest:Explain");
+
+        // The session-wide prompt is left out of the key, so existing entries keep matching
+        let config = test_config(&[("SYSTEM_PROMPT", "Be brief about {language}.")]);
+        assert_eq!(system_prompt_for(&config, "keytest", "Rust").text, "Be brief about Rust.");
+        assert_eq!(key(&config), base_key);
+
+        // The mode's own prompt wins and is fingerprinted, so editing it invalidates the answers
+        let config = test_config(&[("SYSTEM_PROMPT", "Be brief about {language}."), ("SYSTEM_PROMPT_KEYTEST", "Answer in haiku.")]);
+        assert_eq!(system_prompt_for(&config, "keytest", "Rust").text, "Answer in haiku.");
+        let haiku_key = key(&config);
+        assert!(haiku_key.starts_with("keytest:Explain#system:"));
+        assert_ne!(key(&test_config(&[("SYSTEM_PROMPT_KEYTEST", "Answer in limericks.")])), haiku_key);
+
+        // A blank override counts as unset
+        let config = test_config(&[("SYSTEM_PROMPT", "Be brief about {language}."), ("SYSTEM_PROMPT_KEYTEST", "  ")]);
+        assert_eq!(system_prompt_for(&config, "keytest", "Rust").text, "Be brief about Rust.");
+        assert_eq!(key(&config), base_key);
+    }
+
+    #[test]
+    fn line_ranges_are_checked_against_the_code() {
+        assert_eq!(parse_line_range("2-4", 10), Ok((2, 4)));
+        assert_eq!(parse_line_range(" 3 - 3 ", 3), Ok((3, 3)));
+        assert_eq!(parse_line_range("0-3", 10), Err("The range 0-3 is empty; lines are numbered from 1.".to_string()));
+        assert_eq!(parse_line_range("5-2", 10), Err("The range 5-2 is empty; lines are numbered from 1.".to_string()));
+        assert_eq!(parse_line_range("8-12", 10), Err("The code only has 10 lines.".to_string()));
+        assert_eq!(parse_line_range("a-3", 10), Err("'a' is not a line number.".to_string()));
+        assert_eq!(parse_line_range("2-x", 10), Err("'x' is not a line number.".to_string()));
+        assert_eq!(parse_line_range("7", 10), Err("Line ranges look like 10-25.".to_string()));
+
+        assert!(focus_region_note("a\nb\nc\nd", (2, 3)).ends_with("lines 2-3 only, reproduced here:\n\nb\nc"));
+    }
+
+    #[test]
+    fn the_auth_header_follows_the_auth_style() {
+        assert_eq!(auth_header(AuthStyle::ApiKey, Some("secret")), Some(("api-key", "secret".to_string())));
+        assert_eq!(auth_header(AuthStyle::Bearer, Some("secret")), Some(("Authorization", "Bearer secret".to_string())));
+        assert_eq!(auth_header(AuthStyle::None, Some("secret")), None);
+        assert_eq!(auth_header(AuthStyle::ApiKey, None), None);
+        assert_eq!(auth_header(AuthStyle::Bearer, None), None);
+
+        // Only local servers may go without a key
+        assert!(is_local_endpoint("http://localhost:8080/v1/chat/completions"));
+        assert!(is_local_endpoint("http://127.0.0.1:11434/v1/chat/completions"));
+        assert!(is_local_endpoint("http://[::1]:8000/chat"));
+        assert!(!is_local_endpoint("https://example.openai.azure.com/openai/deployments/gpt"));
+    }
+
+    #[test]
+    fn a_refactor_plan_asks_for_committable_steps() {
+        let prompt = default_prompts().render("refactorplan", &[("language", "Rust"), ("code", "fn tangled() { /* ... */ }\n")]);
+        assert!(prompt.starts_with("You are working with Rust code."));
+        assert!(prompt.contains("each of which can be reviewed and committed on its own"));
+        assert!(prompt.contains("suggest a commit message"));
+        assert!(prompt.ends_with("fn tangled() { /* ... */ }\n"));
+    }
+
+    #[test]
+    fn each_setting_reports_the_layer_it_came_from() {
+        let layer = |pairs: &[(&str, &str)]| pairs.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect::<HashMap<_, _>>();
+        let environment = layer(&[("TEMPERATURE", "0.1"), ("MAX_TOKENS_EXPLANATION", "900")]);
+        let env_file = layer(&[("TEMPERATURE", "0.2"), ("TOP_P", "0.5")]);
+        let config_file = layer(&[("TEMPERATURE", "0.3"), ("TOP_P", "0.6"), ("CACHE_LIMIT", "50")]);
+
+        let config = Config::from_sources(&environment, &env_file, &config_file);
+        let setting = |name: &str| config.settings.iter()
+            .find(|setting| setting.name == name)
+            .map(|setting| (setting.value.as_str(), setting.source));
+
+        assert_eq!(setting("TEMPERATURE"), Some(("0.1", SettingSource::Environment)));
+        assert_eq!(setting("TOP_P"), Some(("0.5", SettingSource::EnvFile)));
+        assert_eq!(setting("CACHE_LIMIT"), Some(("50", SettingSource::ConfigFile)));
+        assert_eq!(setting("HISTORY_LIMIT"), Some(("20", SettingSource::Default)));
+        assert_eq!(setting("MAX_TOKENS_EXPLANATION"), Some(("900", SettingSource::Environment)));
+        // Without a default, an unset setting isn't listed at all
+        assert_eq!(setting("MODEL"), None);
+        // What is read is the winning layer's value, and a default only where the code has its own
+        assert_eq!(config.setting("CACHE_LIMIT", 10), 50);
+        assert_eq!(config.value("HISTORY_LIMIT"), None);
+
+        // A given value wins over every layer, and so do the `--max-lines`/`--max-chars` flags set through it
+        let config = config.with("TEMPERATURE", "0.4").with("MAX_LINES", "3");
+        let setting = |name: &str| config.settings.iter()
+            .find(|setting| setting.name == name)
+            .map(|setting| (setting.value.as_str(), setting.source));
+        assert_eq!(setting("TEMPERATURE"), Some(("0.4", SettingSource::Given)));
+        assert_eq!(StreamLimit::from_config(&config).max_lines, Some(3));
+    }
+
+    #[test]
+    fn config_toml_tables_prefix_the_names_of_their_keys() {
+        let file: ConfigFile = toml::from_str("model = 'gpt-4o'\ntemperature = 0.25\n\n[cache]\nlimit = 50\nfailures = true\n\n[max-tokens]\nexplanation = 900\n").unwrap();
+        let settings = file.settings();
+        assert_eq!(settings.get("MODEL").map(String::as_str), Some("gpt-4o"));
+        assert_eq!(settings.get("TEMPERATURE").map(String::as_str), Some("0.25"));
+        assert_eq!(settings.get("CACHE_LIMIT").map(String::as_str), Some("50"));
+        assert_eq!(settings.get("CACHE_FAILURES").map(String::as_str), Some("true"));
+        assert_eq!(settings.get("MAX_TOKENS_EXPLANATION").map(String::as_str), Some("900"));
+        // Settings are strings, numbers and booleans; anything else is refused rather than guessed at
+        assert!(toml::from_str::<ConfigFile>("supported_languages = ['Rust', 'Go']\n").is_err());
+    }
+
+    #[test]
+    fn the_same_prompt_is_cached_separately_per_project() {
+        let mut cache = Cache { namespace: "alpha".to_string(), ..test_cache() };
+        cache.add_entry("Explain this".to_string(), "alpha's answer".to_string());
+        cache.namespace = "beta".to_string();
+        assert!(cache.lookup("Explain this").is_none());
+        cache.add_entry("Explain this".to_string(), "beta's answer".to_string());
+
+        assert_eq!(cache.lookup("Explain this").map(|entry| entry.response.as_str()), Some("beta's answer"));
+        cache.namespace = "alpha".to_string();
+        assert_eq!(cache.lookup("Explain this").map(|entry| entry.response.as_str()), Some("alpha's answer"));
+        assert_eq!(cache.entries.len(), 2);
+    }
+
+    #[test]
+    fn dead_code_prompts_hedge_and_are_keyed_apart_from_other_modes() {
+        let code = "fn unused() {}\nfn main() {}\n";
+        let prompt = default_prompts().render("deadcode", &[("language", "Rust"), ("code", code)]);
+        assert!(prompt.contains("unused functions, unused variables, and unreachable branches"));
+        assert!(prompt.contains("say how confident you are"));
+        assert!(prompt.ends_with(code));
+
+        // Stored under its prefixed key, a dead-code answer can't be mistaken for another mode's
+        let mut cache = test_cache();
+        cache.add_entry(format!("deadcode:{}", prompt), "`unused` is never called.".to_string());
+        assert!(cache.lookup(&prompt).is_none());
+        assert!(cache.lookup(&format!("refactorplan:{}", default_prompts().render("refactorplan", &[("language", "Rust"), ("code", code)]))).is_none());
+    }
+
+    // Answers one HTTP request per body in `bodies`, on as few connections as the client needs, and
+    // returns the endpoint and how many connections were made
+    fn serve(bodies: Vec<&'static str>) -> (String, thread::JoinHandle<usize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/chat", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut bodies = bodies.into_iter();
+            let mut connections = 0;
+            while bodies.len() > 0 {
+                let (stream, _) = listener.accept().unwrap();
+                connections += 1;
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                loop {
+                    let mut content_length = 0;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                        if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                            content_length = length.trim().parse().unwrap();
+                        }
+                        line.clear();
+                    }
+                    if line.is_empty() {
+                        break; // The client closed the connection
+                    }
+                    reader.read_exact(&mut vec![0; content_length]).unwrap();
+                    let Some(body) = bodies.next() else {
+                        break;
+                    };
+                    write!(writer, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+                    if bodies.len() == 0 {
+                        break;
+                    }
+                }
+            }
+            connections
+        });
+        (endpoint, server)
+    }
+
+    const COMPLETION_BODY: &str = r#"{"choices":[{"message":{"role":"assistant","content":"ok"},"finish_reason":"stop","index":0}]}"#;
+
+    #[test]
+    fn one_agent_serves_every_request_of_a_client() {
+        let (endpoint, server) = serve(vec![COMPLETION_BODY, COMPLETION_BODY]);
+        let builds = Cell::new(0);
+        let client = HttpClient::with_agent_factory(endpoint, None, AuthStyle::ApiKey, &Config::default(), |builder| {
+            builds.set(builds.get() + 1);
+            builder.build()
+        });
+        let payload = request_payload(vec![Message { role: "user".to_string(), content: "Say ok".to_string() }], &GenerationSettings::for_mode(&Config::default(), "ask", "Rust", 10), false);
+
+        assert_eq!(send_api_request(&payload, &client).unwrap().text, "ok");
+        assert_eq!(send_api_request(&payload, &client).unwrap().text, "ok");
+
+        assert_eq!(builds.get(), 1);
+        // The second request went out on the kept-alive connection of the first
+        assert_eq!(server.join().unwrap(), 1);
+    }
+
+    #[test]
+    fn the_flow_prompt_asks_for_mermaid_only_when_chosen() {
+        let diagram = build_flow_prompt(&default_prompts(), "Rust", "fn main() {}", true);
+        let steps = build_flow_prompt(&default_prompts(), "Rust", "fn main() {}", false);
+
+        assert!(diagram.contains("```mermaid"));
+        assert!(!steps.contains("Mermaid"));
+        assert!(steps.contains("numbered, step-by-step description"));
+        assert!(diagram.ends_with("fn main() {}") && steps.ends_with("fn main() {}"));
+    }
+
+    #[test]
+    fn languages_are_matched_in_any_case_and_spelled_canonically() {
+        assert_eq!(canonical_language(&Config::default(), "rust").as_deref(), Some("Rust"));
+        assert_eq!(canonical_language(&Config::default(), "  JAVASCRIPT ").as_deref(), Some("JavaScript"));
+        assert_eq!(canonical_language(&Config::default(), "c++").as_deref(), Some("C++"));
+        assert_eq!(canonical_language(&Config::default(), "Cobol"), None);
+        assert_eq!(canonical_language(&Config::default(), ""), None);
+    }
+
+    #[test]
+    fn teach_follow_ups_map_the_three_choices() {
+        assert!(teach_follow_up("1").unwrap().starts_with("Explain the pattern in more detail"));
+        assert!(teach_follow_up(" 2\n").unwrap().contains("everyday analogy"));
+        assert!(teach_follow_up("3").unwrap().contains("simpler, minimal example"));
+        // Anything else ends the lesson
+        for other in ["", "4", "q", "1 2"] {
+            assert_eq!(teach_follow_up(other), None, "{:?}", other);
+        }
+    }
+
+    #[test]
+    fn the_env_file_is_named_after_the_environment() {
+        assert_eq!(env_filename(None), ".env");
+        assert_eq!(env_filename(Some("")), ".env");
+        assert_eq!(env_filename(Some("  ")), ".env");
+        assert_eq!(env_filename(Some("production")), ".env.production");
+        assert_eq!(env_filename(Some(" staging\n")), ".env.staging");
+    }
+
+    #[test]
+    fn shortened_prompts_still_find_their_entries() {
+        let prompt = "Explain this Rust code:\n\nfn main() { println!(\"hello\"); }";
+        // CACHE_PROMPT_STORAGE=preview keeps the first characters, `none` keeps nothing
+        for (preview_chars, stored) in [(12, "Explain this"), (0, "")] {
+            let mut cache = Cache { prompt_preview_chars: Some(preview_chars), ..test_cache() };
+            cache.add_entry(prompt.to_string(), "It prints hello.".to_string());
+            assert_eq!(cache.entries[0].prompt, stored);
+            assert_eq!(cache.lookup(prompt).map(|entry| entry.response.as_str()), Some("It prints hello."));
+
+            // As it would be after saving and loading the file
+            let mut reloaded = Cache::from_records(cache.records());
+            reloaded.rebuild_index();
+            assert_eq!(reloaded.lookup(prompt).map(|entry| entry.response.as_str()), Some("It prints hello."));
+            assert!(reloaded.lookup(stored).is_none());
+        }
+    }
+
+    #[test]
+    fn the_retry_budget_runs_out() {
+        let budget = RetryBudget::new(2);
+        assert!(budget.try_consume());
+        assert_eq!(budget.remaining.load(Ordering::Relaxed), 1);
+        assert!(budget.try_consume());
+        assert_eq!(budget.remaining.load(Ordering::Relaxed), 0);
+        // Spent: no more retries, and the count stays at zero instead of wrapping
+        assert!(!budget.try_consume());
+        assert!(!budget.try_consume());
+        assert_eq!(budget.remaining.load(Ordering::Relaxed), 0);
+        assert!(!RetryBudget::new(0).try_consume());
+    }
+
+    #[test]
+    fn the_logging_prompt_suggests_each_languages_framework() {
+        for (language, framework) in [("Python", "structlog"), ("rust", "the tracing crate"), ("JavaScript", "pino"), ("C++", "spdlog"), ("Java", "SLF4J")] {
+            assert!(build_logging_prompt(&default_prompts(), language, "x").contains(framework), "{}", language);
+        }
+        let fallback = build_logging_prompt(&default_prompts(), "Haskell", "main = pure ()");
+        assert!(fallback.contains("Give example statements using the language's most common structured logging library"));
+        assert!(fallback.ends_with("main = pure ()"));
+    }
+
+    #[test]
+    fn a_byte_order_mark_is_stripped_before_detection() {
+        let content = strip_bom("\u{feff}#include <iostream>\n\nint main() {\n    std::cout << \"hi\";\n    return 0;\n}\n".to_string());
+
+        assert!(content.starts_with("#include"));
+        assert_eq!(extract_language_from_code(&content).name, "C++");
+        // Text without a mark, or with one further in, is left alone
+        assert_eq!(strip_bom("fn main() {}".to_string()), "fn main() {}");
+        assert_eq!(strip_bom("a\u{feff}b".to_string()), "a\u{feff}b");
+    }
+
+    #[test]
+    fn the_migration_prompt_carries_the_version_change_ahead_of_the_code() {
+        let change = "serde_yaml 0.8 -> 0.9, `to_string` now returns a Result";
+        let code = "let text = serde_yaml::to_string(&value);";
+        let prompt = build_migration_prompt(&default_prompts(), "Rust", code, change);
+
+        let change_at = prompt.find(change).unwrap();
+        assert!(change_at < prompt.find(code).unwrap());
+        assert!(prompt.contains("list every change you made"));
+        assert!(prompt.ends_with(code));
+    }
+
+    #[test]
+    fn only_requests_over_the_threshold_need_confirming() {
+        assert!(!needs_cost_confirmation(3999, 4000));
+        assert!(!needs_cost_confirmation(4000, 4000));
+        assert!(needs_cost_confirmation(4001, 4000));
+        assert!(!needs_cost_confirmation(0, 0));
+        assert!(needs_cost_confirmation(1, 0));
+        // Four characters to a token, rounded up
+        let messages = vec![Message { role: "user".to_string(), content: "12345".to_string() }];
+        assert_eq!(estimate_prompt_tokens(&messages), 2);
+    }
+
+    #[test]
+    fn a_reply_failing_validation_is_regenerated() {
+        let fenced = "```rust\nfn main() {}\n```";
+        let client = MockClient::new(vec![reply("Just add a main function."), reply(fenced)]);
+        let mut session = test_session();
+        let request = ModeRequest::prefixed(&Config::default(), "completion", "Rust", "Complete: fn ma".to_string(), 100)
+            .with_validator(CodeFenceValidator);
+        let cache_key = request.full_cache_key();
+
+        let answer = fetch(&client, &mut session, request).unwrap().unwrap();
+
+        assert_eq!(answer.text, fenced);
+        assert_eq!(client.sent().len(), 2);
+        // Only the answer that passed is cached
+        assert_eq!(session.cache().lookup(&cache_key).map(|entry| entry.response.clone()).as_deref(), Some(fenced));
+    }
+
+    #[test]
+    fn a_cached_failure_stops_resending_until_it_expires() {
+        let mut session = test_session();
+        session.cache().failure_ttl_secs = Some(300);
+        let request = || ModeRequest::prefixed(&Config::default(), "explanation", "Rust", "Explain this".to_string(), 100);
+        session.cache().record_failure(&request().full_cache_key(), "the API returned HTTP status 400".to_string());
+
+        // Within the TTL the request isn't sent at all
+        let client = MockClient::new(Vec::new());
+        assert!(fetch(&client, &mut session, request()).unwrap().is_none());
+        assert!(client.sent().is_empty());
+
+        // Once it has expired, the request goes out again
+        session.cache().failures[0].failed_at -= 301;
+        assert!(session.cache().recent_failure(&request().full_cache_key()).is_none());
+        let client = MockClient::new(vec![reply("It explains itself.")]);
+        assert_eq!(fetch(&client, &mut session, request()).unwrap().unwrap().text, "It explains itself.");
+        assert_eq!(client.sent().len(), 1);
+
+        // Without CACHE_FAILURES nothing is recorded
+        let mut cache = test_cache();
+        cache.record_failure("Explain this", "HTTP 400".to_string());
+        assert!(cache.failures.is_empty());
+    }
+
+    #[test]
+    fn only_the_latest_unexpired_failure_of_a_request_is_kept() {
+        let mut cache = test_cache();
+        cache.failure_ttl_secs = Some(300);
+        cache.record_failure("Explain this", "HTTP 400".to_string());
+        cache.record_failure("Explain this", "HTTP 400 again".to_string());
+        // Another project's failure for the same prompt is kept apart
+        cache.namespace = "other".to_string();
+        cache.record_failure("Explain this", "HTTP 400".to_string());
+        assert_eq!(cache.failures.len(), 2);
+
+        // Replaying the file keeps the latest failure per project and prompt
+        let mut reloaded = Cache::from_records(cache.journal.clone());
+        let messages: Vec<_> = reloaded.failures.iter().map(|failure| failure.message.as_str()).collect();
+        assert_eq!(messages, ["HTTP 400 again", "HTTP 400"]);
+
+        // Expired ones are dropped on load, and left out when the file is rewritten
+        reloaded.failure_ttl_secs = Some(300);
+        reloaded.failures[0].failed_at -= 301;
+        reloaded.prune_failures();
+        assert_eq!(reloaded.failures.len(), 1);
+        assert!(reloaded.needs_rewrite);
+        cache.failures[1].failed_at -= 301;
+        assert_eq!(cache.records().iter().filter(|record| matches!(record, CacheRecord::Failure(_))).count(), 1);
+    }
+
+    #[test]
+    fn a_truncated_reply_is_continued_and_joined() {
+        let first = Reply { truncated: true, ..reply("fn main() {\n    println!(") };
+        let client = MockClient::new(vec![first, reply("\"hi\");\n}")]);
+        let mut session = test_session();
+        session.assume_yes = true;
+        let messages = build_messages("You help with code.", &[], "Write hello world");
+        let settings = GenerationSettings::for_mode(&Config::default(), "completion", "Rust", 7);
+
+        let answer = send_messages(&client, &session, messages.clone(), &settings).unwrap().unwrap();
+
+        assert_eq!(answer.text, "fn main() {\n    println!(\"hi\");\n}");
+        assert!(!answer.truncated);
+        let sent = client.sent();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1][..messages.len()], messages[..]);
+        assert_eq!(sent[1][messages.len()], Message { role: "assistant".to_string(), content: "fn main() {\n    println!(".to_string() });
+        assert_eq!(sent[1][messages.len() + 1].content, CONTINUE_PROMPT);
+    }
+
+    #[test]
+    fn a_truncated_reply_is_kept_as_is_without_anyone_to_ask() {
+        let client = MockClient::new(vec![Reply { truncated: true, ..reply("fn main() {") }]);
+        let session = test_session();
+        let settings = GenerationSettings::for_mode(&Config::default(), "completion", "Rust", 7);
+
+        let answer = send_messages(&client, &session, build_messages("", &[], "Write main"), &settings).unwrap().unwrap();
+
+        assert_eq!(answer.text, "fn main() {");
+        assert_eq!(client.sent().len(), 1);
+    }
+
+    #[test]
+    fn a11y_menu_entry_is_only_offered_for_web_languages() {
+        assert!(menu_option_available("12", "TypeScript"));
+        assert!(menu_option_available("12", &canonical_language(&Config::default(), "html").unwrap()));
+        assert!(!menu_option_available("12", "Rust"));
+        assert!(!menu_option_available("12", "Python"));
+        assert!(menu_option_available("11", "Rust"));
+        assert!(menu_option_available("13", "Rust"));
+    }
+
+    #[test]
+    fn async_direction_maps_menu_choices() {
+        assert_eq!(async_direction("1\n"), Some(AsyncDirection::ToAsync));
+        assert_eq!(async_direction(" 2 "), Some(AsyncDirection::ToSync));
+        assert_eq!(async_direction("3"), None);
+        assert_eq!(async_direction(""), None);
+
+        let prompts = default_prompts();
+        let to_async = build_convert_async_prompt(&prompts, "Python", "def f(): pass", AsyncDirection::ToAsync);
+        let to_sync = build_convert_async_prompt(&prompts, "Python", "async def f(): pass", AsyncDirection::ToSync);
+        assert!(to_async.contains("synchronous code to idiomatic asynchronous code using asyncio"));
+        assert!(to_sync.contains("asynchronous code, written with asyncio and async/await, to idiomatic synchronous code"));
+    }
+
+    #[test]
+    fn a_diagram_is_saved_next_to_its_code_file() {
+        assert_eq!(diagram_path(Some("src/parser.rs"), DiagramFormat::Mermaid), Path::new("src/parser.mmd"));
+        assert_eq!(diagram_path(Some("Makefile"), DiagramFormat::Mermaid), Path::new("Makefile.mmd"));
+        assert_eq!(diagram_path(None, DiagramFormat::PlantUml), Path::new("diagram.puml"));
+    }
+
+    #[test]
+    fn a_malformed_price_is_ignored_rather_than_quoted() {
+        let price = |config: Config| config.optional_setting::<f64>("PRICE_PER_1K_TOKENS");
+        assert_eq!(price(test_config(&[("PRICE_PER_1K_TOKENS", "0.002")])), Some(0.002));
+        assert_eq!(price(test_config(&[("PRICE_PER_1K_TOKENS", "two cents")])), None);
+        assert_eq!(price(Config::default()), None);
+    }
+
+    #[test]
+    fn lines_focus_the_explanation_or_are_refused() {
+        let path = scratch_path("focus.rs");
+        fs::write(&path, "fn a() {}\nfn b() {}\nfn c() {}\n").unwrap();
+        let client = MockClient::new(vec![reply("`b` does nothing.")]);
+        let mut session = test_session();
+        session.code_file = Some(path.clone());
+        session.focus_lines = Some("2-2".to_string());
+
+        code_explanation(&client, "Rust", &mut session).unwrap();
+        let prompt = client.sent()[0].last().unwrap().content.clone();
+        assert!(prompt.ends_with("Focus your answer on lines 2-2 only, reproduced here:\n\nfn b() {}"));
+
+        // A range past the end is an error rather than a silently unfocused answer
+        session.focus_lines = Some("2-5".to_string());
+        let error = code_explanation(&client, "Rust", &mut session).unwrap_err();
+        assert!(error.to_string().contains("The code only has 3 lines."), "{}", error);
+
+        // So is a range for code that is sent in parts
+        session.config = test_config(&[("CONTEXT_LIMIT", "700")]);
+        fs::write(&path, "fn filler() { let x = 1; }\n".repeat(400)).unwrap();
+        session.focus_lines = Some("1-2".to_string());
+        let error = refactoring_suggestions(&client, "Rust", &mut session).unwrap_err();
+        assert!(error.to_string().contains("a line range can't be applied to parts"), "{}", error);
+        assert_eq!(client.sent().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flow_format_picks_mermaid_or_steps_without_asking() {
+        let flow = |format: &str| flow_format(&test_config(&[("FLOW_FORMAT", format)]));
+        assert_eq!(flow_format(&Config::default()).unwrap(), None);
+        assert_eq!(flow(" Mermaid ").unwrap(), Some(true));
+        assert_eq!(flow("steps").unwrap(), Some(false));
+        assert!(flow("ascii").unwrap_err().to_string().contains("Use mermaid or steps"));
+
+        // A test run answers nothing, so the chosen format is what goes out
+        let path = scratch_path("flow.rs");
+        fs::write(&path, "fn main() {}\n").unwrap();
+        let client = MockClient::new(vec![reply("1. main returns.")]);
+        let mut session = test_session();
+        session.code_file = Some(path.clone());
+        session.config = test_config(&[("FLOW_FORMAT", "steps")]);
+        describe_flow(&client, "Rust", &mut session).unwrap();
+        assert!(client.sent()[0].last().unwrap().content.contains("numbered, step-by-step description"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn html_and_typescript_can_be_picked_and_pass_the_language_check() {
+        let html = canonical_language(&Config::default(), "html").unwrap();
+        let typescript = canonical_language(&Config::default(), "typescript").unwrap();
+        assert_eq!((html.as_str(), typescript.as_str()), ("HTML", "TypeScript"));
+
+        let page = "<!DOCTYPE html>\n<html>\n<body>\n  <img src=\"logo.png\">\n  <script>console.log(\"hi\");</script>\n</body>\n</html>\n";
+        assert!(check_language(&CodeInput { text: page.to_string(), extension_language: None }, &html, &test_session()));
+        for file in ["index.html", "index.HTM"] {
+            assert!(check_language(&CodeInput::from_file(file, String::new()), &html, &test_session()), "{}", file);
+        }
+        assert!(check_language(&CodeInput::from_file("App.tsx", String::new()), &typescript, &test_session()));
+        assert!(check_language(&CodeInput::from_file("App.jsx", String::new()), "JavaScript", &test_session()));
+        assert!(!check_language(&CodeInput::from_file("index.html", String::new()), &typescript, &test_session()));
+    }
+
+    #[test]
+    fn answers_go_to_the_history_file_of_the_session() {
+        let path = scratch_path("history.jsonl");
+        let client = MockClient::new(vec![reply("It adds one.")]);
+        let mut session = test_session();
+        session.history_file = Some(path.clone());
+
+        fetch(&client, &mut session, ModeRequest::prefixed(&Config::default(), "explanation", "Rust", "Explain inc".to_string(), 100)).unwrap();
+
+        let entry = last_history_entry(Some(&path)).unwrap();
+        assert_eq!((entry.mode.as_str(), entry.prompt.as_str(), entry.response.as_str()), ("explanation", "Explain inc", "It adds one."));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1630 @@
+// The modes: their prompt templates, how each request is built, and the actions behind the menu
+use super::*;
+
+// The instruction each mode sends, overridable per mode in PROMPTS_FILE. Placeholders in braces are filled
+// in at runtime: `{language}` and `{code}` everywhere, plus the mode-specific ones named in each template
+pub(crate) const DEFAULT_PROMPT_TEMPLATES: &[(&str, &str)] = &[
+    ("completion", "You are working with {language} code. Your task is to complete the given code:\n\n{code}"),
+    ("contextcompletion", "You are working with {language} code. Here is the whole file, for context only:\n\n{context}\n\nYour task is to complete only the following region of that file, using the imports, types and functions it defines. Return just the completed region:\n\n{code}"),
+    ("explanation", "You are working with {language} code. Your task is to explain the following code:\n\n{code}"),
+    ("refactoring", "You are working with {language} code. Your task is to provide refactoring suggestions for the following code:\n\n{code}"),
+    ("warnings", "You are working with {language} code. Your task is to explain each of the following compiler warnings and how to resolve it:\n\n{warnings}"),
+    ("proptest", "You are working with {language} code. Your task is to identify the invariants of the following code and write property-based tests for them using {framework}:\n\n{code}"),
+    ("refactorplan", "You are working with {language} code. Your task is to plan a refactoring of the following code as a sequence of small steps, each of which can be reviewed and committed on its own. For every step, describe the change and suggest a commit message:\n\n{code}"),
+    ("deadcode", "You are working with {language} code. Your task is to point out likely dead code in the following code: unused functions, unused variables, and unreachable branches. This is a heuristic review without the rest of the project, so say how confident you are about each finding:\n\n{code}"),
+    ("flow", "You are working with {language} code. Your task is to describe the control flow of the following code, including the order of calls and the branches taken. {format}\n\n{code}"),
+    ("teach", "You are working with {language} code. Your task is to teach the pattern used in the following code to a learner. Start with a concise, high-level overview of a few sentences; more detail will be requested later:\n\n{code}"),
+    ("async", "You are working with {language} code. Your task is to {task}. Keep the behavior the same and explain any changes to error handling or ordering:\n\n{code}"),
+    ("a11y", "You are working with {language} UI code. Your task is to review the following code for accessibility and internationalization problems: missing ARIA attributes, likely color contrast issues, gaps in keyboard navigation, and hardcoded user-facing strings that should be localized. For each finding, show the fix:\n\n{code}"),
+    ("logging", "You are working with {language} code. Your task is to suggest where the following code should emit structured logs or metrics, and why. Give example statements using {framework}:\n\n{code}"),
+    ("migrate", "You are working with {language} code. Your task is to migrate the following code across this dependency version change: {change}\n\nReturn the updated code and list every change you made:\n\n{code}"),
+    ("refactordiff", "You are working with {language} code. Your task is to refactor the following code. Return the complete refactored code in a single fenced code block, without any explanation:\n\n{code}"),
+    ("translate", "You are working with {language} code. Your task is to translate the following code into idiomatic {target}, using its own conventions and standard library rather than a line-by-line copy. Return only the translated code in a single fenced code block:\n\n{code}"),
+    ("question", "You are working with {language} code. Answer the following question:\n\n{question}"),
+    ("docs", "You are working with {language} code. Your task is to document the following code with idiomatic {style}: describe what each public item does, its parameters, return value and errors, without restating the obvious. Return the complete code with the comments inserted and nothing else changed, in a single fenced code block:\n\n{code}"),
+    ("compare", "You are working with {language} code. Here are two implementations of the same thing.\n\nFirst version:\n\n{first}\n\nSecond version:\n\n{second}\n\nYour task is to compare them: explain how they differ and the trade-offs in correctness (including edge cases one handles and the other doesn't), performance and style. Finish by saying which you would choose and when the other would be the better choice."),
+    ("fixerror", "You are working with {language} code. The following code produces this error:\n\n{error}\n\nThe code:\n\n{code}\n\nYour task is to fix it. Return the corrected code in a single fenced code block, then explain what caused the error and how the fix resolves it."),
+    ("diagram", "You are working with {language} code. Your task is to diagram the structure of the following code: {kind}. Use the names from the code. {format} Follow it with at most a few sentences on anything left out:\n\n{code}"),
+    ("diff", "You are reviewing a change to {language} code, given as a unified diff in which lines starting with + were added and lines starting with - were removed. Your task is to explain in plain words what the change does and why it was likely made, then flag its risks: bugs it may introduce, changes in behavior, and edge cases or tests it misses:\n\n{diff}"),
+    ("critique", "You are working with {language} code. The following answer was given to a request about this code:\n\n{code}\n\nThe answer:\n\n{draft}\n\nCritique the answer: look for mistakes, missed opportunities and anything unclear. Then reply with the improved answer in full, in the same format, and nothing else, so that it can replace the original."),
+    ("review", "You are working with {language} code. Your task is to audit the following code for bugs, unhandled edge cases and security issues. List each concrete issue with a severity (critical, high, medium or low), the lines involved, why it is a problem and how to fix it. Say so plainly if you find nothing:\n\n{code}"),
+];
+// The per-mode instruction templates: the defaults, with any from the prompts file on top
+#[derive(Clone)]
+pub(crate) struct PromptTemplates {
+    pub(crate) templates: HashMap<String, String>,
+}
+
+impl PromptTemplates {
+    pub(crate) fn defaults() -> PromptTemplates {
+        let templates = DEFAULT_PROMPT_TEMPLATES.iter()
+            .map(|&(mode, template)| (mode.to_string(), template.to_string()))
+            .collect();
+        PromptTemplates { templates }
+    }
+
+    // A missing file keeps the defaults; one that can't be parsed is an error rather than silently ignored
+    pub(crate) fn load(filename: &str) -> Result<PromptTemplates, AssistantError> {
+        let PromptTemplates { mut templates } = PromptTemplates::defaults();
+        if !Path::new(filename).exists() {
+            return Ok(PromptTemplates { templates });
+        }
+        let overrides: HashMap<String, String> = serde_json::from_str(&fs::read_to_string(filename)?)
+            .map_err(|error| AssistantError::Config(format!("Could not parse the prompt templates in '{}': {}", filename, error)))?;
+        for (mode, template) in overrides {
+            if !templates.contains_key(&mode) {
+                warn!("'{}' has a template for unknown mode '{}', ignoring it.", filename, mode);
+                continue;
+            }
+            templates.insert(mode, template);
+        }
+        Ok(PromptTemplates { templates })
+    }
+
+    // Fills in `{name}` placeholders in one pass, so braces inside the substituted code are left alone;
+    // unknown placeholders are kept as written
+    pub(crate) fn render(&self, mode: &str, values: &[(&str, &str)]) -> String {
+        let template = &self.templates[mode];
+        let mut prompt = String::with_capacity(template.len());
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            prompt.push_str(&rest[..start]);
+            let after = &rest[start + 1..];
+            let value = after.find('}')
+                .and_then(|end| values.iter().find(|(name, _)| *name == &after[..end]).map(|(_, value)| (end, value)));
+            match value {
+                Some((end, value)) => {
+                    prompt.push_str(value);
+                    rest = &after[end + 1..];
+                }
+                None => {
+                    prompt.push('{');
+                    rest = after;
+                }
+            }
+        }
+        prompt.push_str(rest);
+        prompt
+    }
+}
+
+// The system message sent ahead of each prompt, flagged when it came from a user override
+pub(crate) struct SystemPrompt {
+    pub(crate) text: String,
+    pub(crate) is_override: bool,
+}
+
+pub(crate) fn code_completion(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("completion", &[("language", specified_language), ("code", &code_content)]);
+
+    let request = ModeRequest::new(&session.config, "completion", specified_language, prompt, 500) // Increased token limit for code completion
+        .with_validator(CodeFenceValidator);
+    let Some(reply) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+    let blocks = extract_code_blocks(&reply.text);
+    let completion = deliver_code_reply(session, &reply, &blocks)?;
+    if session.config.flag("VALIDATE_OUTPUT") && !blocks.is_empty() {
+        check_compiles(specified_language, &blocks.join("\n\n"));
+    }
+    offer_to_append_completion(&completion, session.interactive)
+}
+
+// Completes a region of a file with the whole file sent along, so the model can see its imports and types.
+// The context is read from `--context` or asked for; the region is the usual code input
+pub(crate) fn context_completion(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let context = match arg_value("--context") {
+        Some(path) => read_text_file(&path, session)?,
+        None => loop {
+            let path = prompt_line("Context file (the whole file the code belongs to): ");
+            check_cancelled()?;
+            if path.is_empty() {
+                warn!("No context file given.");
+                return Ok(());
+            }
+            match read_text_file(&path, session) {
+                Ok(content) => break content,
+                Err(error) => say!("Could not read '{}' ({}). Please try again.", path, error),
+            }
+        },
+    };
+    say!("Now the region to complete.");
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("contextcompletion", &[("language", specified_language), ("context", &context), ("code", &code_content)]);
+
+    let request = ModeRequest::prefixed(&session.config, "contextcompletion", specified_language, prompt, 500)
+        .keyed_on(&context)
+        .with_validator(CodeFenceValidator);
+    let Some(reply) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+    let blocks = extract_code_blocks(&reply.text);
+    let completion = deliver_code_reply(session, &reply, &blocks)?;
+    offer_to_append_completion(&completion, session.interactive)
+}
+
+// Prints and saves a response that is mostly code, returning what was delivered
+pub(crate) fn deliver_code_reply(session: &Session, reply: &Reply, blocks: &[String]) -> Result<String, AssistantError> {
+    // `--code-only` keeps just the fenced code, so the output can be redirected straight into a source file
+    if session.code_only && !blocks.is_empty() {
+        let code = blocks.join("\n\n");
+        match &session.json {
+            Some(json) => print_json_reply(json, reply, &code)?,
+            None => println!("{}", code),
+        }
+        write_output(session, &code)?;
+        Ok(code)
+    } else {
+        deliver_reply(session, reply)?;
+        Ok(reply.text.clone())
+    }
+}
+
+// Collecting completions in one file makes it easy to keep iterating on them
+pub(crate) fn offer_to_append_completion(completion: &str, interactive: bool) -> Result<(), AssistantError> {
+    if interactive && ask_yes_no(&format!("Append the completion to '{}'?", CODE_OUTPUT_FILE)) {
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(CODE_OUTPUT_FILE)?;
+        writeln!(file, "{}", completion)?;
+        say!("Appended to {}.", CODE_OUTPUT_FILE);
+    }
+    Ok(())
+}
+
+pub(crate) fn code_explanation(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let focus = focus_region(session, &code_content)?;
+    let budget = match context_fit(&session.prompts, &session.config, "explanation", specified_language, &code_content)? {
+        ContextFit::Whole => None,
+        ContextFit::Parts(_) if focus.is_some() => return Err(focus_in_parts_error()),
+        ContextFit::Parts(parts) => return respond_in_parts(client, session, "explanation", specified_language, parts),
+        ContextFit::Trim(budget) => Some(budget),
+    };
+    let code = code_to_send(&code_content, specified_language, budget, focus);
+    let mut prompt = session.prompts.render("explanation", &[("language", specified_language), ("code", &code)]);
+    if let Some(range) = focus {
+        prompt.push_str(&focus_region_note(&code_content, range));
+    }
+
+    respond(client, session, ModeRequest::new(&session.config, "explanation", specified_language, prompt, 500)) // Increased token limit for code explanation
+}
+
+pub(crate) fn refactoring_suggestions(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let focus = focus_region(session, &code_content)?;
+    let budget = match context_fit(&session.prompts, &session.config, "refactoring", specified_language, &code_content)? {
+        ContextFit::Whole => None,
+        ContextFit::Parts(_) if focus.is_some() => return Err(focus_in_parts_error()),
+        ContextFit::Parts(parts) => return respond_in_parts(client, session, "refactoring", specified_language, parts),
+        ContextFit::Trim(budget) => Some(budget),
+    };
+    let code = code_to_send(&code_content, specified_language, budget, focus);
+    let mut prompt = session.prompts.render("refactoring", &[("language", specified_language), ("code", &code)]);
+    if let Some(range) = focus {
+        prompt.push_str(&focus_region_note(&code_content, range));
+    }
+
+    let request = ModeRequest::new(&session.config, "refactoring", specified_language, prompt, 500); // Increased token limit for refactoring suggestions
+    respond_reviewed(client, session, request, specified_language, &code)
+}
+
+// `--review-passes N` or REVIEW_PASSES; 0, the default, sends a single request
+pub(crate) fn review_passes_setting(config: &Config) -> Result<u32, AssistantError> {
+    let Some(value) = arg_value("--review-passes").or_else(|| config.value("REVIEW_PASSES").map(str::to_string)) else {
+        return Ok(0);
+    };
+    value.trim().parse()
+        .map_err(|_| AssistantError::Config(format!("--review-passes {} is not a number of passes.", value)))
+}
+
+// Like `respond`, but with `review_passes` rounds in which the model critiques the previous answer and
+// returns an improved one. Each round is cached like any request; only the final answer is shown,
+// and the drafts before it go to stderr in verbose mode
+pub(crate) fn respond_reviewed(client: &dyn ApiClient, session: &mut Session, request: ModeRequest, language: &str, code: &str) -> Result<(), AssistantError> {
+    if session.review_passes == 0 {
+        return respond(client, session, request);
+    }
+    // Streaming would print every draft, and the critique prompts are not the user's to edit
+    let stream = std::mem::replace(&mut session.stream, false);
+    let review_prompt = std::mem::replace(&mut session.review_prompt, false);
+    let result = fetch(client, session, request).and_then(|reply| match reply {
+        Some(reply) => review_reply(client, session, reply, language, code).map(Some),
+        None => Ok(None),
+    });
+    session.stream = stream;
+    session.review_prompt = review_prompt;
+    if let Some(reply) = result? {
+        deliver_reply(session, &reply)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn review_reply(client: &dyn ApiClient, session: &mut Session, mut reply: Reply, language: &str, code: &str) -> Result<Reply, AssistantError> {
+    for pass in 1..=session.review_passes {
+        verbose!("Draft {}:\n{}", pass, reply.text);
+        say!("Reviewing the response (pass {} of {})...", pass, session.review_passes);
+        let prompt = session.prompts.render("critique", &[("language", language), ("code", code), ("draft", &reply.text)]);
+        let request = ModeRequest::prefixed(&session.config, "critique", language, prompt, 700); // The whole answer comes back
+        // The prompt carries everything the round needs, so it is sent without the conversation and
+        // kept out of it; the conversation only ever holds the first request and the final answer
+        let conversation = std::mem::replace(&mut session.conversation, Conversation::new(0));
+        let improved = fetch(client, session, request);
+        session.conversation = conversation;
+        match improved? {
+            Some(improved) => reply = Reply { cached: reply.cached && improved.cached, ..improved },
+            None => break,
+        }
+    }
+    session.conversation.replace_last_reply(reply.text.clone());
+    Ok(reply)
+}
+
+// A piece of a file too big for one request, with its 1-based line range
+pub(crate) struct CodePart {
+    pub(crate) text: String,
+    pub(crate) first_line: usize,
+    pub(crate) last_line: usize,
+}
+
+// The pieces `code` has to be sent in to fit CONTEXT_LIMIT along with the instructions and the response;
+// a single piece when it fits as it is
+pub(crate) fn context_parts(prompts: &PromptTemplates, config: &Config, mode: &str, language: &str, code: &str) -> Vec<CodePart> {
+    let context_limit: usize = config.setting("CONTEXT_LIMIT", DEFAULT_CONTEXT_LIMIT);
+    let response_tokens = GenerationSettings::for_mode(config, mode, language, 500).max_tokens as usize;
+    let instruction_chars = prompts.render(mode, &[("language", language), ("code", "")]).chars().count()
+        + system_prompt_for(config, mode, language).text.chars().count()
+        + PART_NOTE_CHARS;
+    // ~4 characters per token, as in the cost estimate; a limit too small to be useful still leaves some room
+    let budget = (context_limit.saturating_sub(response_tokens) * 4).saturating_sub(instruction_chars).max(MIN_PART_CHARS);
+    if code.chars().count() <= budget {
+        return vec![CodePart { text: code.to_string(), first_line: 1, last_line: code.lines().count() }];
+    }
+    split_code(code, budget)
+}
+
+// What happens to code that doesn't fit CONTEXT_LIMIT, from CONTEXT_STRATEGY
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ContextStrategy {
+    Chunk, // Sent in parts, one request each
+    Trim,  // Cut down to fit, keeping the code nearest the focus
+    Fail,
+}
+
+pub(crate) fn context_strategy(config: &Config) -> Result<ContextStrategy, AssistantError> {
+    match config.value("CONTEXT_STRATEGY").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" | "chunk" => Ok(ContextStrategy::Chunk),
+        "trim" => Ok(ContextStrategy::Trim),
+        "fail" => Ok(ContextStrategy::Fail),
+        other => Err(AssistantError::Config(format!("Unknown CONTEXT_STRATEGY '{}'. Use chunk, trim or fail.", other))),
+    }
+}
+
+// How code is sent given CONTEXT_LIMIT
+pub(crate) enum ContextFit {
+    Whole, // It fits as it is
+    Parts(Vec<CodePart>),
+    Trim(usize), // Trimmed to this many tokens
+}
+
+// Chunking keeps the character estimate it has always used; trimming has to know which lines to drop,
+// so it and `fail` measure with `count_tokens`
+pub(crate) fn context_fit(prompts: &PromptTemplates, config: &Config, mode: &str, language: &str, code: &str) -> Result<ContextFit, AssistantError> {
+    let strategy = context_strategy(config)?;
+    if strategy == ContextStrategy::Chunk {
+        let parts = context_parts(prompts, config, mode, language, code);
+        return Ok(if parts.len() > 1 { ContextFit::Parts(parts) } else { ContextFit::Whole });
+    }
+    let context_limit: usize = config.setting("CONTEXT_LIMIT", DEFAULT_CONTEXT_LIMIT);
+    let response_tokens = GenerationSettings::for_mode(config, mode, language, 500).max_tokens as usize;
+    let instructions = prompts.render(mode, &[("language", language), ("code", "")]);
+    let budget = context_limit.saturating_sub(response_tokens + count_tokens(&instructions) + count_tokens(&system_prompt_for(config, mode, language).text));
+    let tokens = count_tokens(code);
+    if tokens <= budget {
+        return Ok(ContextFit::Whole);
+    }
+    match strategy {
+        ContextStrategy::Fail => Err(AssistantError::Input(format!(
+            "The code is about {} tokens, but only {} fit CONTEXT_LIMIT ({}) next to the instructions and the response. \
+             Send a smaller piece, raise CONTEXT_LIMIT, or set CONTEXT_STRATEGY to chunk or trim",
+            tokens, budget, context_limit
+        ))),
+        _ => Ok(ContextFit::Trim(budget)),
+    }
+}
+
+// The code as it goes into the prompt: trimmed to `budget` tokens when one is given, less the lines the
+// focus note repeats
+pub(crate) fn code_to_send(code: &str, language: &str, budget: Option<usize>, focus: Option<(usize, usize)>) -> String {
+    let Some(budget) = budget else {
+        return code.to_string();
+    };
+    let note_tokens = focus.map_or(0, |range| count_tokens(&focus_region_note(code, range)));
+    let trimmed = trim_code(code, language, budget.saturating_sub(note_tokens), focus);
+    let tokens = count_tokens(&trimmed);
+    info!("Notice: the code is about {} tokens, more than the {} that fit CONTEXT_LIMIT, so it was trimmed to about {}.", count_tokens(code), budget, tokens);
+    if tokens > budget {
+        warn!("the focused lines alone are larger than CONTEXT_LIMIT allows; the request may be rejected.");
+    }
+    trimmed
+}
+
+// Estimates tokens the way BPE tokenizers split code: a run of letters, digits and underscores costs a token
+// per 4 characters, every other visible character one token, and a line break with its indentation one more.
+// Closer for code than counting characters, which overestimates indentation and underestimates punctuation
+pub(crate) fn count_tokens(text: &str) -> usize {
+    let mut tokens = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(character) = chars.next() {
+        if character.is_alphanumeric() || character == '_' {
+            let mut length: usize = 1;
+            while chars.next_if(|next| next.is_alphanumeric() || *next == '_').is_some() {
+                length += 1;
+            }
+            tokens += length.div_ceil(4);
+        } else if character == '\n' {
+            while chars.next_if(|next| next.is_whitespace()).is_some() {}
+            tokens += 1;
+        } else if !character.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    tokens
+}
+
+// How far a trimmed block has been cut down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BlockTrim {
+    Kept,
+    Signature, // Only its first line (and a closing brace) is kept
+    Omitted,
+}
+
+// Cuts `code` down to about `budget` tokens. Blank lines and comments go first; then, starting with the
+// top-level items farthest from the focus (or from the top of the file without one), bodies are replaced
+// with an `... omitted ...` marker that keeps the signature, and finally whole items. The focused lines
+// are never dropped, and kept lines are unchanged, so the focus note's line numbers still apply
+pub(crate) fn trim_code(code: &str, language: &str, budget: usize, focus: Option<(usize, usize)>) -> String {
+    let (focus_start, focus_end) = focus.unwrap_or((1, 1));
+    let in_focus = |number: usize| focus.is_some_and(|(start, end)| (start..=end).contains(&number));
+    let comment_prefixes = comment_prefixes(language);
+    let lines: Vec<(usize, &str)> = code.lines().enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|&(number, line)| {
+            let trimmed = line.trim();
+            in_focus(number) || !(trimmed.is_empty() || comment_prefixes.iter().any(|prefix| trimmed.starts_with(prefix)))
+        })
+        .collect();
+    let line_tokens: Vec<usize> = lines.iter().map(|(_, line)| count_tokens(line) + 1).collect();
+
+    // Top-level items, split where braces are balanced and the next line starts unindented, as in `split_code`
+    let mut blocks = Vec::new();
+    let (mut start, mut depth) = (0, 0i64);
+    for (index, (_, line)) in lines.iter().enumerate() {
+        depth += line.matches('{').count() as i64 - line.matches('}').count() as i64;
+        let starts_item = lines.get(index + 1).is_some_and(|(_, next)| !next.starts_with([' ', '\t']));
+        if depth <= 0 && starts_item || index + 1 == lines.len() {
+            blocks.push(start..index + 1);
+            start = index + 1;
+        }
+    }
+    let marker_tokens = count_tokens(&format!("{} ... omitted lines 1000-1000 ...", comment_prefixes[0])) + 1;
+    // Adjacent omitted items share one marker, so an omitted item only costs one when the item before it is kept
+    let cost = |block: &std::ops::Range<usize>, trim: BlockTrim| match trim {
+        BlockTrim::Kept => line_tokens[block.clone()].iter().sum(),
+        BlockTrim::Signature => line_tokens[block.start] + marker_tokens + line_tokens[block.end - 1],
+        BlockTrim::Omitted => marker_tokens,
+    };
+    let total = |trims: &[BlockTrim]| -> usize {
+        blocks.iter().zip(trims).enumerate().map(|(index, (block, &trim))| match trim {
+            BlockTrim::Omitted if index > 0 && trims[index - 1] == BlockTrim::Omitted => 0,
+            _ => cost(block, trim),
+        }).sum()
+    };
+    let mut trims = vec![BlockTrim::Kept; blocks.len()];
+
+    // Farthest first; blocks holding focused lines are left whole
+    let distance = |block: &std::ops::Range<usize>| {
+        let (first, last) = (lines[block.start].0, lines[block.end - 1].0);
+        first.saturating_sub(focus_end).max(focus_start.saturating_sub(last))
+    };
+    let mut order: Vec<usize> = (0..blocks.len())
+        .filter(|&index| !blocks[index].clone().any(|line| in_focus(lines[line].0)))
+        .collect();
+    order.sort_by_key(|&index| std::cmp::Reverse(distance(&blocks[index])));
+    for step in [BlockTrim::Signature, BlockTrim::Omitted] {
+        for &index in &order {
+            if total(&trims) <= budget {
+                break;
+            }
+            // Short bodies cost less than the marker that would replace them
+            if step == BlockTrim::Signature && (blocks[index].len() <= 2 || cost(&blocks[index], step) >= cost(&blocks[index], BlockTrim::Kept)) {
+                continue;
+            }
+            trims[index] = step;
+        }
+    }
+
+    let mut output: Vec<String> = Vec::new();
+    let mut omitted: Option<(usize, usize)> = None;
+    let marker = |indent: &str, (first, last): (usize, usize)| format!("{}{} ... omitted lines {}-{} ...", indent, comment_prefixes[0], first, last);
+    for (block, trim) in blocks.iter().zip(&trims) {
+        let (first, last) = (lines[block.start].0, lines[block.end - 1].0);
+        if *trim == BlockTrim::Omitted {
+            omitted = Some((omitted.map_or(first, |(start, _)| start), last));
+            continue;
+        }
+        if let Some(range) = omitted.take() {
+            output.push(marker("", range));
+        }
+        match trim {
+            BlockTrim::Signature => {
+                let (signature, closing) = (lines[block.start].1, lines[block.end - 1].1);
+                let body = lines[block.start + 1].1;
+                output.push(signature.to_string());
+                // A lone closing brace is kept so the braces still pair up
+                if matches!(closing.trim(), "}" | "};") {
+                    output.push(marker(&body[..body.len() - body.trim_start().len()], (lines[block.start + 1].0, lines[block.end - 2].0)));
+                    output.push(closing.to_string());
+                } else {
+                    output.push(marker(&body[..body.len() - body.trim_start().len()], (lines[block.start + 1].0, last)));
+                }
+            }
+            _ => output.extend(lines[block.clone()].iter().map(|(_, line)| line.to_string())),
+        }
+    }
+    if let Some(range) = omitted {
+        output.push(marker("", range));
+    }
+    output.join("\n")
+}
+
+// Line comment markers, the first of which starts the `... omitted ...` lines; block comment lines are
+// recognized by their usual `/*` and ` * ` starts
+pub(crate) fn comment_prefixes(language: &str) -> &'static [&'static str] {
+    match language.to_ascii_lowercase().as_str() {
+        "python" | "ruby" => &["#"],
+        "sql" => &["--"],
+        _ => &["//", "/*", "* ", "*/"],
+    }
+}
+
+// Splits code into pieces of at most `budget` characters, preferring to cut where a top-level item ends:
+// braces are balanced and the next line starts unindented, as between two functions. Without such a place,
+// e.g. inside one long function, the cut falls on the last line that fits
+pub(crate) fn split_code(code: &str, budget: usize) -> Vec<CodePart> {
+    let lines: Vec<&str> = code.lines().collect();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while start < lines.len() {
+        let (mut end, mut size, mut depth, mut boundary) = (start, 0, 0i64, None);
+        while end < lines.len() {
+            let line_size = lines[end].chars().count() + 1;
+            if size + line_size > budget && end > start {
+                break;
+            }
+            size += line_size;
+            depth += lines[end].matches('{').count() as i64 - lines[end].matches('}').count() as i64;
+            end += 1;
+            let starts_item = lines.get(end).is_some_and(|next| !next.trim().is_empty() && !next.starts_with([' ', '\t']));
+            if depth <= 0 && starts_item {
+                boundary = Some(end);
+            }
+        }
+        let cut = if end < lines.len() { boundary.unwrap_or(end) } else { end };
+        parts.push(CodePart { text: lines[start..cut].join("\n"), first_line: start + 1, last_line: cut });
+        start = cut;
+    }
+    parts
+}
+
+// Sends each part as a request of its own, noting which part it is, and shows the answers joined under
+// a heading per part. Each part is cached on its own and sent without the conversation, which is what
+// would not have fit; the conversation is left as it was
+pub(crate) fn respond_in_parts(client: &dyn ApiClient, session: &mut Session, mode: &str, language: &str, parts: Vec<CodePart>) -> Result<(), AssistantError> {
+    say!("The code is too large for CONTEXT_LIMIT, so it is sent in {} parts.", parts.len());
+    let conversation = std::mem::replace(&mut session.conversation, Conversation::new(0));
+    let mut sections = Vec::new();
+    let (mut streamed, mut truncated, mut cached) = (true, false, true);
+    let mut elapsed = None;
+    let total = parts.len();
+    for (number, part) in parts.into_iter().enumerate() {
+        let heading = format!("## Part {} of {} (lines {}-{})", number + 1, total, part.first_line, part.last_line);
+        if session.stream {
+            say!("{}", heading);
+        }
+        let mut prompt = session.prompts.render(mode, &[("language", language), ("code", &part.text)]);
+        prompt.push_str(&format!(
+            "\n\nThis is part {} of {} of a larger file (lines {}-{}). The other parts are sent separately, so cover only this part.",
+            number + 1, total, part.first_line, part.last_line
+        ));
+        session.conversation.clear();
+        let reply = fetch(client, session, ModeRequest::new(&session.config, mode, language, prompt, 500));
+        let reply = match reply {
+            Ok(Some(reply)) => reply,
+            outcome => {
+                session.conversation = conversation;
+                return outcome.map(|_| say!("Stopped after {} of {} parts.", number, total));
+            }
+        };
+        streamed &= reply.streamed;
+        truncated |= reply.truncated;
+        cached &= reply.cached;
+        if let Some(part_elapsed) = reply.elapsed {
+            elapsed = Some(elapsed.unwrap_or_default() + part_elapsed);
+        }
+        sections.push(format!("{}\n\n{}", heading, reply.text.trim_end()));
+    }
+    session.conversation = conversation;
+    let reply = Reply { text: sections.join("\n\n"), streamed, truncated, usage: None, cached, prompt_hash: None, alternatives: Vec::new(), elapsed };
+    deliver_reply(session, &reply)
+}
+
+// Printed locally, so help works offline and with a missing or invalid API key
+pub(crate) fn help_how_to_use() {
+    println!("{}", HELP_TEXT);
+}
+
+// The line range to focus on: `--lines`, checked against the code, or else the answer to a question when
+// there is a terminal to ask
+pub(crate) fn focus_region(session: &Session, code_content: &str) -> Result<Option<(usize, usize)>, AssistantError> {
+    match &session.focus_lines {
+        Some(lines) => parse_line_range(lines, code_content.lines().count())
+            .map(Some)
+            .map_err(|message| AssistantError::Input(format!("--lines {}: {}", lines, message))),
+        None if session.interactive => Ok(ask_for_focus_region(code_content)),
+        None => Ok(None),
+    }
+}
+
+// Each part is answered on its own, so a range of the whole file has nowhere to go
+pub(crate) fn focus_in_parts_error() -> AssistantError {
+    AssistantError::Input("The code is sent in parts to fit CONTEXT_LIMIT, and a line range can't be applied to parts. \
+        Set CONTEXT_STRATEGY=trim to keep the focused lines and trim the rest, or send only the lines you need".to_string())
+}
+
+// Optionally narrows the analysis to a line range such as `10-25`; Enter keeps the whole input
+pub(crate) fn ask_for_focus_region(code_content: &str) -> Option<(usize, usize)> {
+    let line_count = code_content.lines().count();
+    loop {
+        ask!("Focus on a line range (e.g. 10-25) or press Enter for the whole code: ");
+
+        let input = read_answer().unwrap_or_default();
+        if input.trim().is_empty() {
+            return None;
+        }
+        match parse_line_range(&input, line_count) {
+            Ok(range) => return Some(range),
+            Err(message) => say!("{}", message),
+        }
+    }
+}
+
+// Parses an inclusive, 1-based `start-end` range and checks it against the number of lines
+pub(crate) fn parse_line_range(input: &str, line_count: usize) -> Result<(usize, usize), String> {
+    let (start, end) = input.trim().split_once('-')
+        .ok_or_else(|| "Line ranges look like 10-25.".to_string())?;
+    let start: usize = start.trim().parse().map_err(|_| format!("'{}' is not a line number.", start.trim()))?;
+    let end: usize = end.trim().parse().map_err(|_| format!("'{}' is not a line number.", end.trim()))?;
+
+    if start == 0 || start > end {
+        Err(format!("The range {}-{} is empty; lines are numbered from 1.", start, end))
+    } else if end > line_count {
+        Err(format!("The code only has {} lines.", line_count))
+    } else {
+        Ok((start, end))
+    }
+}
+
+// The full code stays in the prompt as context; this repeats the focused lines so they are unambiguous
+pub(crate) fn focus_region_note(code_content: &str, (start, end): (usize, usize)) -> String {
+    let excerpt: Vec<&str> = code_content.lines().skip(start - 1).take(end - start + 1).collect();
+    format!("\n\nFocus your answer on lines {}-{} only, reproduced here:\n\n{}", start, end, excerpt.join("\n"))
+}
+
+pub(crate) fn explain_warnings(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    // The warnings are compiler output rather than code, so check_language is skipped here
+    say!("Paste the compiler warnings (type '{}' on a new line when finished):", end_marker(&session.config));
+    let warnings = read_multiline_input(&session.config);
+    if warnings.trim().is_empty() {
+        warn!("No warnings provided. Aborting.");
+        return Ok(());
+    }
+
+    let code_content = if session.interactive && ask_yes_no("Include the code that produced these warnings?") {
+        get_code_input(session.code_file.as_deref(), session)?.map(|input| input.text)
+    } else {
+        None
+    };
+
+    let prompt = build_warnings_prompt(&session.prompts, specified_language, &warnings, code_content.as_deref());
+    respond(client, session, ModeRequest::new(&session.config, "warnings", specified_language, prompt, 500))
+}
+
+pub(crate) fn build_warnings_prompt(prompts: &PromptTemplates, specified_language: &str, warnings: &str, code_content: Option<&str>) -> String {
+    let mut prompt = prompts.render("warnings", &[("language", specified_language), ("warnings", warnings)]);
+    if let Some(code) = code_content {
+        prompt.push_str(&format!("\n\nThe warnings were produced by this code:\n\n{}", code));
+    }
+    prompt
+}
+
+pub(crate) fn generate_property_tests(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = build_property_tests_prompt(&session.prompts, specified_language, &code_content);
+    let request = ModeRequest::prefixed(&session.config, "proptest", specified_language, prompt, 800) // Invariants plus generated tests need more room
+        .with_validator(CodeFenceValidator);
+    respond(client, session, request)
+}
+
+pub(crate) fn build_property_tests_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str) -> String {
+    prompts.render("proptest", &[("language", specified_language), ("framework", property_test_framework(specified_language)), ("code", code_content)])
+}
+
+// The most common property-based testing framework for each supported language
+pub(crate) fn property_test_framework(language: &str) -> &'static str {
+    match language.to_ascii_lowercase().as_str() {
+        "python" => "Hypothesis",
+        "rust" => "proptest",
+        "javascript" => "fast-check",
+        "c++" => "RapidCheck",
+        "java" => "jqwik",
+        _ => "the language's most common property-based testing library",
+    }
+}
+
+pub(crate) fn refactor_plan(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("refactorplan", &[("language", specified_language), ("code", &code_content)]);
+    respond(client, session, ModeRequest::prefixed(&session.config, "refactorplan", specified_language, prompt, 800)) // A multi-step plan needs more room
+}
+
+pub(crate) fn find_dead_code(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("deadcode", &[("language", specified_language), ("code", &code_content)]);
+    respond(client, session, ModeRequest::prefixed(&session.config, "deadcode", specified_language, prompt, 600))
+}
+
+pub(crate) fn describe_flow(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let mermaid = match flow_format(&session.config)? {
+        Some(mermaid) => mermaid,
+        None => session.interactive && ask_yes_no("Produce the flow as a Mermaid diagram?"),
+    };
+    let prompt = build_flow_prompt(&session.prompts, specified_language, &code_content, mermaid);
+    respond(client, session, ModeRequest::prefixed(&session.config, "flow", specified_language, prompt, 700))
+}
+
+// Whether the flow is a Mermaid diagram, from `--mermaid` or FLOW_FORMAT (`mermaid` or `steps`); None when
+// neither says, which leaves it to a question at a terminal
+pub(crate) fn flow_format(config: &Config) -> Result<Option<bool>, AssistantError> {
+    if env::args().skip(1).any(|arg| arg == "--mermaid") {
+        return Ok(Some(true));
+    }
+    match config.value("FLOW_FORMAT").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" => Ok(None),
+        "mermaid" => Ok(Some(true)),
+        "steps" => Ok(Some(false)),
+        other => Err(AssistantError::Config(format!("Unknown FLOW_FORMAT '{}'. Use mermaid or steps.", other))),
+    }
+}
+
+pub(crate) fn build_flow_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str, mermaid: bool) -> String {
+    let format_instruction = if mermaid {
+        "Write it as a Mermaid sequence diagram (or flowchart, if there are no interacting parts) in a ```mermaid code block."
+    } else {
+        "Write it as a numbered, step-by-step description."
+    };
+    prompts.render("flow", &[("language", specified_language), ("format", format_instruction), ("code", code_content)])
+}
+
+// Teaches the pattern in a snippet in layers: a cached overview first, then follow-ups on request
+pub(crate) fn teach(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("teach", &[("language", specified_language), ("code", &code_content)]);
+    let request = ModeRequest::prefixed(&session.config, "teach", specified_language, prompt.clone(), 500);
+    let system_prompt = request.system_prompt.text.clone();
+    let settings = request.settings.clone();
+    let Some(overview) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+    deliver_reply(session, &overview)?;
+    if !session.interactive {
+        return Ok(());
+    }
+
+    // Follow-ups build on the whole conversation so far, so each answer stays on the same pattern
+    let mut messages = vec![
+        Message { role: "system".to_string(), content: system_prompt },
+        Message { role: "user".to_string(), content: prompt },
+        Message { role: "assistant".to_string(), content: overview.text },
+    ];
+    loop {
+        say!("1. Show more detail");
+        say!("2. Give an analogy");
+        say!("3. Show a simpler example");
+        say!("4. Back to the main menu");
+        ask!("Choose an option: ");
+
+        // End of input, as in a scripted `--mode teach` run, also returns
+        let Some(choice) = read_answer().filter(|choice| choice.trim() != "4") else {
+            return Ok(());
+        };
+        check_cancelled()?;
+        let Some(follow_up) = teach_follow_up(&choice) else {
+            say!("Invalid option, please try again.");
+            continue;
+        };
+
+        messages.push(Message { role: "user".to_string(), content: follow_up.to_string() });
+        let Some(answer) = send_messages(client, session, messages.clone(), &settings)? else {
+            messages.pop();
+            continue;
+        };
+        deliver_reply(session, &answer)?;
+        messages.push(Message { role: "assistant".to_string(), content: answer.text });
+    }
+}
+
+pub(crate) fn teach_follow_up(choice: &str) -> Option<&'static str> {
+    match choice.trim() {
+        "1" => Some("Explain the pattern in more detail: how each part works and why it is structured this way."),
+        "2" => Some("Give an everyday analogy that captures the idea behind this pattern."),
+        "3" => Some("Show a simpler, minimal example that uses the same pattern."),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AsyncDirection {
+    ToAsync,
+    ToSync,
+}
+
+pub(crate) fn convert_async(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    if async_conventions(specified_language).is_none() {
+        warn!("{} has no widely used async/await model, so the conversion may not be idiomatic.", specified_language);
+    }
+    // Without anyone to ask, e.g. in a batch, code is converted to async, the usual direction
+    let direction = if session.interactive { ask_async_direction() } else { Some(AsyncDirection::ToAsync) };
+    let Some(direction) = direction else {
+        return Ok(());
+    };
+    let prompt = build_convert_async_prompt(&session.prompts, specified_language, &code_content, direction);
+    let request = ModeRequest::prefixed(&session.config, "async", specified_language, prompt, 700)
+        .with_validator(CodeFenceValidator);
+    respond(client, session, request)
+}
+
+// None when input ends before a direction is chosen, which cancels the conversion
+pub(crate) fn ask_async_direction() -> Option<AsyncDirection> {
+    loop {
+        say!("1. Synchronous -> asynchronous");
+        say!("2. Asynchronous -> synchronous");
+        ask!("Choose a direction: ");
+
+        let choice = read_answer()?;
+        match async_direction(&choice) {
+            Some(direction) => return Some(direction),
+            None => say!("Invalid option, please try again."),
+        }
+    }
+}
+
+pub(crate) fn async_direction(choice: &str) -> Option<AsyncDirection> {
+    match choice.trim() {
+        "1" => Some(AsyncDirection::ToAsync),
+        "2" => Some(AsyncDirection::ToSync),
+        _ => None,
+    }
+}
+
+pub(crate) fn build_convert_async_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str, direction: AsyncDirection) -> String {
+    let conventions = async_conventions(specified_language).unwrap_or("the language's usual concurrency primitives");
+    let task = match direction {
+        AsyncDirection::ToAsync => format!("convert the following synchronous code to idiomatic asynchronous code using {}", conventions),
+        AsyncDirection::ToSync => format!("convert the following asynchronous code, written with {}, to idiomatic synchronous code", conventions),
+    };
+    prompts.render("async", &[("language", specified_language), ("task", &task), ("code", code_content)])
+}
+
+// How each language usually expresses asynchronous code; None where there is no mainstream async model
+pub(crate) fn async_conventions(language: &str) -> Option<&'static str> {
+    match language.to_ascii_lowercase().as_str() {
+        "python" => Some("asyncio and async/await"),
+        "rust" => Some("async/await on the tokio runtime"),
+        "javascript" => Some("Promises and async/await"),
+        "java" => Some("CompletableFuture"),
+        _ => None,
+    }
+}
+
+pub(crate) fn a11y_review(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    if !is_web_language(specified_language) {
+        warn!("The accessibility review is only available for web languages (HTML, JavaScript, TypeScript).");
+        return Ok(());
+    }
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = build_a11y_prompt(&session.prompts, specified_language, &code_content);
+    respond(client, session, ModeRequest::prefixed(&session.config, "a11y", specified_language, prompt, 700))
+}
+
+pub(crate) fn build_a11y_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str) -> String {
+    prompts.render("a11y", &[("language", specified_language), ("code", code_content)])
+}
+
+// Entries that only apply to some languages are left out of the menu for the others; the rest keep their numbers
+pub(crate) fn menu_option_available(option: &str, language: &str) -> bool {
+    option != "12" || is_web_language(language)
+}
+
+pub(crate) fn is_web_language(language: &str) -> bool {
+    ["HTML", "JavaScript", "TypeScript"].iter().any(|web| web.eq_ignore_ascii_case(language))
+}
+
+pub(crate) fn add_logging(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = build_logging_prompt(&session.prompts, specified_language, &code_content);
+    respond(client, session, ModeRequest::prefixed(&session.config, "logging", specified_language, prompt, 600))
+}
+
+pub(crate) fn build_logging_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str) -> String {
+    prompts.render("logging", &[("language", specified_language), ("framework", logging_framework(specified_language)), ("code", code_content)])
+}
+
+// The most common structured logging framework for each supported language
+pub(crate) fn logging_framework(language: &str) -> &'static str {
+    match language.to_ascii_lowercase().as_str() {
+        "python" => "the standard logging module (or structlog)",
+        "rust" => "the tracing crate",
+        "javascript" => "pino",
+        "c++" => "spdlog",
+        "java" => "SLF4J",
+        _ => "the language's most common structured logging library",
+    }
+}
+
+// Documents the code in the comment style of its language, returning the code with the comments in place
+pub(crate) fn generate_docs(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("docs", &[("language", specified_language), ("style", doc_comment_style(specified_language)), ("code", &code_content)]);
+    let request = ModeRequest::prefixed(&session.config, "docs", specified_language, prompt, 1000) // The whole file comes back with the comments
+        .with_validator(CodeFenceValidator);
+    let Some(reply) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+    deliver_code_reply(session, &reply, &extract_code_blocks(&reply.text)).map(|_| ())
+}
+
+// Asks for two versions of the same code, the first like any code input and the second from `--against`
+// or asked for, and has the model weigh them against each other
+pub(crate) fn compare_snippets(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    say!("The first version:");
+    let Some(first) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    say!("The second version:");
+    let Some(second) = get_code_input(arg_value("--against").as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&first, specified_language, session) || !check_language(&second, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let prompt = session.prompts.render("compare", &[("language", specified_language), ("first", &first.text), ("second", &second.text)]);
+    let request = ModeRequest::prefixed(&session.config, "compare", specified_language, prompt, 800) // Two snippets to cover
+        .keyed_on(&snippet_pair_key(&first.text, &second.text));
+    respond(client, session, request)
+}
+
+// Asks for the code like any code input, then for the error it produces, from `--error` or pasted, and
+// returns the corrected code with an explanation of the fix
+pub(crate) fn fix_compiler_error(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let error = match arg_value("--error") {
+        Some(path) => read_text_file(&path, session)?,
+        None => {
+            say!("Paste the compiler or runtime error (type '{}' on a new line when finished):", end_marker(&session.config));
+            read_multiline_input(&session.config)
+        }
+    };
+    check_cancelled()?;
+    let error = error.trim();
+    if error.is_empty() {
+        warn!("No error provided. Aborting.");
+        return Ok(());
+    }
+    let prompt = session.prompts.render("fixerror", &[("language", specified_language), ("code", &input.text), ("error", error)]);
+    // The same code with a different error needs a different fix
+    let request = ModeRequest::prefixed(&session.config, "fixerror", specified_language, prompt, 1000) // The whole fixed code plus the explanation
+        .keyed_on(&snippet_pair_key(&input.text, error))
+        .with_validator(CodeFenceValidator);
+    let Some(reply) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+    deliver_code_reply(session, &reply, &extract_code_blocks(&reply.text)).map(|_| ())
+}
+
+// Draws the call graph or the class relationships of the code, checks that the answer holds a well-formed
+// diagram block, and offers to save the diagram on its own as a `.mmd` or `.puml` file
+pub(crate) fn draw_diagram(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let format = diagram_format(&session.config)?;
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let class_relationships = session.interactive && ask_yes_no("Diagram the class relationships instead of the call graph?");
+    let prompt = build_diagram_prompt(&session.prompts, specified_language, &input.text, class_relationships, format);
+    let request = ModeRequest::prefixed(&session.config, "diagram", specified_language, prompt, 800).with_validator(DiagramValidator(format));
+    let Some(reply) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+    // An answer that failed validation twice is still shown, but there is no diagram to save
+    let diagram = format.diagram_in(&reply.text).ok();
+    deliver_code_reply(session, &reply, diagram.as_slice())?;
+    if let Some(diagram) = diagram {
+        offer_to_save_diagram(&diagram, format, session)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn build_diagram_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str, class_relationships: bool, format: DiagramFormat) -> String {
+    let kind = if class_relationships {
+        "its class relationships, showing each type with its main fields and methods and which types inherit from, implement, contain or use which"
+    } else {
+        "its call graph, showing which functions and methods call which"
+    };
+    prompts.render("diagram", &[("language", specified_language), ("kind", kind), ("format", format.instruction()), ("code", code_content)])
+}
+
+// The diagram language asked for; DIAGRAM_FORMAT picks it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DiagramFormat {
+    Mermaid,
+    PlantUml,
+}
+
+// The diagram types a Mermaid block can start with
+pub(crate) const MERMAID_DIAGRAM_TYPES: &[&str] = &[
+    "graph", "flowchart", "classDiagram", "sequenceDiagram", "stateDiagram", "stateDiagram-v2", "erDiagram",
+    "journey", "gantt", "pie", "mindmap", "timeline", "gitGraph", "C4Context", "C4Container", "C4Component",
+];
+
+pub(crate) fn diagram_format(config: &Config) -> Result<DiagramFormat, AssistantError> {
+    match config.value("DIAGRAM_FORMAT").unwrap_or_default().trim().to_ascii_lowercase().as_str() {
+        "" | "mermaid" => Ok(DiagramFormat::Mermaid),
+        "plantuml" | "puml" => Ok(DiagramFormat::PlantUml),
+        other => Err(AssistantError::Config(format!("Unknown DIAGRAM_FORMAT '{}'. Use mermaid or plantuml.", other))),
+    }
+}
+
+impl DiagramFormat {
+    pub(crate) fn instruction(self) -> &'static str {
+        match self {
+            DiagramFormat::Mermaid => "Write it in Mermaid syntax in a single ```mermaid code block that starts with the diagram type, such as flowchart TD or classDiagram.",
+            DiagramFormat::PlantUml => "Write it in PlantUML syntax in a single ```plantuml code block, from @startuml to @enduml.",
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            DiagramFormat::Mermaid => "mmd",
+            DiagramFormat::PlantUml => "puml",
+        }
+    }
+
+    // The body of the first block tagged with this format, if it is closed and well-formed
+    pub(crate) fn diagram_in(self, response: &str) -> Result<String, String> {
+        let tags: &[&str] = match self {
+            DiagramFormat::Mermaid => &["mermaid"],
+            DiagramFormat::PlantUml => &["plantuml", "puml"],
+        };
+        let mut lines = response.lines();
+        lines.find(|line| line.trim_start().strip_prefix("```").is_some_and(|tag| tags.contains(&tag.trim().to_ascii_lowercase().as_str())))
+            .ok_or_else(|| format!("no ```{} block", tags[0]))?;
+        let mut body = Vec::new();
+        loop {
+            match lines.next() {
+                Some(line) if line.trim_start().starts_with("```") => break,
+                Some(line) => body.push(line),
+                None => return Err(format!("the ```{} block is not closed", tags[0])),
+            }
+        }
+        let body = body.join("\n");
+        let content: Vec<&str> = body.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with("%%") && !line.starts_with('\'')).collect();
+        match self {
+            DiagramFormat::Mermaid => {
+                let diagram_type = content.first().and_then(|line| line.split_whitespace().next()).unwrap_or_default();
+                if !MERMAID_DIAGRAM_TYPES.contains(&diagram_type) {
+                    return Err(format!("the Mermaid block does not start with a diagram type (got '{}')", diagram_type));
+                }
+                if content.len() < 2 {
+                    return Err("the Mermaid diagram is empty".to_string());
+                }
+            }
+            DiagramFormat::PlantUml => {
+                if content.first().is_none_or(|line| !line.starts_with("@startuml")) || content.last().is_none_or(|line| !line.starts_with("@enduml")) {
+                    return Err("the PlantUML block does not run from @startuml to @enduml".to_string());
+                }
+                if content.len() < 3 {
+                    return Err("the PlantUML diagram is empty".to_string());
+                }
+            }
+        }
+        Ok(body)
+    }
+}
+
+pub(crate) struct DiagramValidator(DiagramFormat);
+
+impl ResponseValidator for DiagramValidator {
+    fn validate(&self, response: &str) -> Result<(), String> {
+        self.0.diagram_in(response).map(|_| ())
+    }
+}
+
+// Named after the code file, or `diagram.<ext>` for pasted code, in the current directory. `--yes`
+// saves without asking; a batch worker or a script without it leaves the diagram in the response
+pub(crate) fn offer_to_save_diagram(diagram: &str, format: DiagramFormat, session: &Session) -> Result<(), AssistantError> {
+    let path = diagram_path(session.code_file.as_deref(), format);
+    let shown = path.display();
+    let save = (session.interactive || session.assume_yes) && confirm(&format!("Save the diagram to '{}'?", shown), session.assume_yes);
+    if !save {
+        return Ok(());
+    }
+    // `--yes` agrees to saving, not to replacing a file that is already there
+    if path.exists() {
+        if !session.interactive {
+            warn!("'{}' already exists, so the diagram was not saved.", shown);
+            return Ok(());
+        }
+        if !confirm(&format!("'{}' already exists. Overwrite it?", shown), false) {
+            return Ok(());
+        }
+    }
+    fs::write(&path, format!("{}\n", diagram.trim_end()))?;
+    say!("Diagram written to {}.", shown);
+    Ok(())
+}
+
+// Next to the code file, named after it; `diagram.<extension>` in the current directory for pasted code
+pub(crate) fn diagram_path(code_file: Option<&str>, format: DiagramFormat) -> PathBuf {
+    code_file.filter(|file| Path::new(file).file_stem().is_some())
+        .map_or_else(|| PathBuf::from("diagram"), PathBuf::from)
+        .with_extension(format.extension())
+}
+
+// Both snippets' hashes, in order, so an answer is reused only when both match; swapping them asks again,
+// since the analysis refers to them as first and second
+pub(crate) fn snippet_pair_key(first: &str, second: &str) -> String {
+    format!("{:016x}{:016x}", fnv1a_hash(first), fnv1a_hash(second))
+}
+
+pub(crate) fn doc_comment_style(language: &str) -> &'static str {
+    match language.to_ascii_lowercase().as_str() {
+        "rust" => "rustdoc `///` comments, with `# Errors` and `# Panics` sections where they apply",
+        "python" => "PEP 257 docstrings",
+        "javascript" | "typescript" => "JSDoc `/** */` comments with @param and @returns tags",
+        "java" => "Javadoc `/** */` comments with @param, @return and @throws tags",
+        "c++" => "Doxygen `///` comments with @param and @return",
+        "c#" => "XML documentation `///` comments",
+        "go" => "Go doc comments that start with the item's name",
+        "ruby" => "YARD comments",
+        _ => "documentation comments in the language's usual style",
+    }
+}
+
+pub(crate) fn migrate_version(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let change = prompt_line("Describe the version change (e.g. \"library X 1.x -> 2.x, method foo renamed to bar\"): ");
+    if change.is_empty() {
+        warn!("No version change described. Aborting.");
+        return Ok(());
+    }
+    let prompt = build_migration_prompt(&session.prompts, specified_language, &code_content, &change);
+    let request = ModeRequest::prefixed(&session.config, "migrate", specified_language, prompt, 800)
+        .with_validator(CodeFenceValidator);
+    respond(client, session, request)
+}
+
+// Asks for the whole refactored code and shows it as a diff against the original
+pub(crate) fn refactor_with_diff(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("refactordiff", &[("language", specified_language), ("code", &code_content)]);
+    let request = ModeRequest::prefixed(&session.config, "refactordiff", specified_language, prompt, 1000) // The whole file comes back, not just the changes
+        .with_validator(CodeFenceValidator);
+    let Some(reply) = fetch(client, session, request)? else {
+        return Ok(());
+    };
+
+    // Only a single block can be lined up with the original; anything else is shown as it came
+    match extract_code_blocks(&reply.text).as_slice() {
+        [refactored] => {
+            let plain_diff = render_diff(&code_content, refactored, false);
+            if plain_diff.is_empty() {
+                say!("The refactored code is identical to the original.");
+            }
+            match &session.json {
+                Some(json) => print_json_reply(json, &reply, &plain_diff)?,
+                None if plain_diff.is_empty() => return Ok(()),
+                None => print!("{}", render_diff(&code_content, refactored, use_color())),
+            }
+            write_output(session, &plain_diff)
+        }
+        _ => {
+            say!("The response did not contain a single code block to compare, showing it as-is:");
+            deliver_reply(session, &reply)
+        }
+    }
+}
+
+pub(crate) fn security_review(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, specified_language, session) {
+        warn!("The detected language in the code does not match the specified language. Aborting.");
+        return Ok(());
+    }
+    let code_content = input.text;
+    let prompt = session.prompts.render("review", &[("language", specified_language), ("code", &code_content)]);
+    respond(client, session, ModeRequest::prefixed(&session.config, "review", specified_language, prompt, 1200)) // A thorough audit needs room
+}
+
+// Explains a change given as a unified diff. Under `--mode` without `--file`, a diff piped in is read from stdin,
+// as in `--mode explain-diff < changes.diff`. The language check is skipped, since a diff is not code in one
+// language; the language of the files it changes is used instead
+pub(crate) fn explain_diff(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let diff = if arg_value("--mode").is_some() && session.code_file.is_none() && !io::stdin().is_terminal() {
+        let mut diff = String::new();
+        io::stdin().read_to_string(&mut diff)?;
+        diff
+    } else {
+        match get_code_input(session.code_file.as_deref(), session)? {
+            Some(input) => input.text,
+            None => return Ok(()),
+        }
+    };
+    if diff.trim().is_empty() {
+        warn!("No diff provided.");
+        return Ok(());
+    }
+    if !looks_like_diff(&diff) {
+        warn!("This does not look like a unified diff (no 'diff --git', '---'/'+++' or '@@' lines). Aborting.");
+        return Ok(());
+    }
+    let language = diff_language(&diff, &session.config).unwrap_or(specified_language);
+    let prompt = session.prompts.render("diff", &[("language", language), ("diff", &diff)]);
+    respond(client, session, ModeRequest::prefixed(&session.config, "diff", language, prompt, 800))
+}
+
+pub(crate) fn looks_like_diff(text: &str) -> bool {
+    let lines: Vec<&str> = text.lines().collect();
+    lines.iter().any(|line| line.starts_with("diff --git ") || line.starts_with("@@ "))
+        || lines.windows(2).any(|pair| pair[0].starts_with("--- ") && pair[1].starts_with("+++ "))
+}
+
+// The supported language most of the diff's changed files are written in, from their extensions
+pub(crate) fn diff_language(diff: &str, config: &Config) -> Option<&'static str> {
+    let supported = supported_languages(config);
+    let mut counts: HashMap<&'static str, usize> = HashMap::new();
+    for line in diff.lines() {
+        let Some(path) = line.strip_prefix("+++ ").or_else(|| line.strip_prefix("--- ")) else {
+            continue;
+        };
+        // `/dev/null` stands in for the missing side of an added or deleted file
+        let path = path.split('\t').next().unwrap_or(path).trim();
+        if let Some(language) = language_from_extension(path).filter(|language| supported.iter().any(|name| name == language)) {
+            *counts.entry(language).or_default() += 1;
+        }
+    }
+    counts.into_iter().max_by_key(|&(language, count)| (count, std::cmp::Reverse(language))).map(|(language, _)| language)
+}
+
+// Ports code from one language to another; both are asked for, the source defaulting to the session's language
+// and the target taken from `--to` when given
+pub(crate) fn translate_code(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    say!("Source language:");
+    let source = ask_for_language(&session.config, Some(specified_language))?;
+    let target = match arg_value("--to") {
+        Some(target) => canonical_language(&session.config, &target)
+            .ok_or_else(|| AssistantError::Config(format!("Unsupported language '{}'. Use one of: {}.", target, supported_languages(&session.config).join(", "))))?,
+        None => {
+            say!("Target language:");
+            ask_for_language(&session.config, None)?
+        }
+    };
+    check_cancelled()?;
+    if source == target {
+        warn!("The source and target languages are the same. Aborting.");
+        return Ok(());
+    }
+    let Some(input) = get_code_input(session.code_file.as_deref(), session)? else {
+        return Ok(());
+    };
+    if !check_language(&input, &source, session) {
+        warn!("The detected language in the code does not match the source language. Aborting.");
+        return Ok(());
+    }
+    let prompt = session.prompts.render("translate", &[("language", &source), ("target", &target), ("code", &input.text)]);
+    // The answer is target-language code, so the system prompt and token limit follow the target.
+    // Both languages are in the key, so Python->Rust and Python->Java never share an answer
+    let cache_key = format!("translate:{}->{}:{}", source, target, prompt);
+    let request = ModeRequest { cache_key, ..ModeRequest::new(&session.config, "translate", &target, prompt, 1000) }
+        .with_validator(CodeFenceValidator);
+    respond(client, session, request)
+}
+
+// An open question about the language, with no code to paste and so no language check
+pub(crate) fn ask_question(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let question = prompt_line("Your question: ");
+    check_cancelled()?;
+    if question.is_empty() {
+        warn!("No question asked.");
+        return Ok(());
+    }
+    let prompt = session.prompts.render("question", &[("language", specified_language), ("question", &question)]);
+    respond(client, session, ModeRequest::prefixed(&session.config, "question", specified_language, prompt, 600))
+}
+
+// Asks for more of the latest response: this session's, or in a new session the last one in the history file.
+// Only the new text is shown; the saved response, the conversation and the history get the joined whole
+pub(crate) fn continue_last_response(client: &dyn ApiClient, specified_language: &str, session: &mut Session) -> Result<(), AssistantError> {
+    let (prompt, previous) = match session.conversation.last_exchange() {
+        Some((prompt, reply)) => (prompt.to_string(), reply.to_string()),
+        None => match last_history_entry(session.history_file.as_deref()) {
+            Some(entry) => (entry.prompt, entry.response),
+            None => {
+                say!("There is no earlier response to continue.");
+                return Ok(());
+            }
+        },
+    };
+    say!("Continuing: {}", one_line_preview(&previous, 100));
+    let mut messages = vec![Message { role: "system".to_string(), content: system_prompt_for(&session.config, "continue", specified_language).text }];
+    // The conversation already ends with the exchange being continued
+    if session.conversation.last_exchange().is_some() {
+        messages.extend(session.conversation.messages.iter().cloned());
+    } else {
+        messages.push(Message { role: "user".to_string(), content: prompt.clone() });
+        messages.push(Message { role: "assistant".to_string(), content: previous.clone() });
+    }
+    messages.push(Message { role: "user".to_string(), content: CONTINUE_PROMPT.to_string() });
+
+    let settings = GenerationSettings::for_mode(&session.config, "continue", specified_language, 500);
+    let Some(reply) = send_messages(client, session, messages, &settings)? else {
+        return Ok(());
+    };
+    let joined = join_continuation(&previous, &reply.text);
+    let mut continuation = Reply { text: joined.clone(), ..reply };
+    append_history(session.history_file.as_deref(), "continue", specified_language, &prompt, &continuation);
+    continuation.text = joined[previous.len()..].to_string();
+    if session.conversation.last_exchange().is_some() {
+        session.conversation.replace_last_reply(joined.clone());
+    } else {
+        session.conversation.record(prompt, joined.clone());
+    }
+    session.answered += 1;
+    match &session.json {
+        Some(json) => print_json_reply(json, &continuation, &continuation.text)?,
+        None => print_reply(&continuation, session),
+    }
+    write_output(session, &joined)
+}
+
+// Joins a continuation onto the text it continues, dropping the start of it when the model repeated
+// the end of what it had already written
+pub(crate) fn join_continuation(previous: &str, rest: &str) -> String {
+    let longest = previous.len().min(rest.len()).min(MAX_REPEATED_CHARS);
+    let repeated = (MIN_REPEATED_CHARS..=longest).rev()
+        .find(|&length| rest.is_char_boundary(length) && previous.ends_with(&rest[..length]))
+        .unwrap_or(0);
+    format!("{}{}", previous, &rest[repeated..])
+}
+
+// Runs the language's own compiler over the code and reports whether it is valid; only warns when the
+// toolchain is missing, since the check is optional. Languages without a check are skipped
+pub(crate) fn check_compiles(language: &str, code: &str) {
+    let (file_name, commands): (&str, &[&[&str]]) = match language.to_ascii_lowercase().as_str() {
+        // A library crate, so a completion without `main` still passes; metadata only, nothing is linked
+        "rust" => ("completion.rs", &[&["rustc", "--edition", "2021", "--crate-type", "lib", "--emit=metadata", "completion.rs"]]),
+        "python" => ("completion.py", &[&["python3", "-m", "py_compile", "completion.py"], &["python", "-m", "py_compile", "completion.py"]]),
+        _ => return,
+    };
+    let directory = env::temp_dir().join(format!("ai_code_assistant_check_{}", process::id()));
+    let result = fs::create_dir_all(&directory)
+        .and_then(|_| fs::write(directory.join(file_name), code))
+        .and_then(|_| {
+            // The first interpreter that exists wins, e.g. `python3` before `python`
+            let mut outcome = Err(io::Error::from(io::ErrorKind::NotFound));
+            for command in commands {
+                outcome = process::Command::new(command[0]).args(&command[1..]).current_dir(&directory).output();
+                if !matches!(&outcome, Err(error) if error.kind() == io::ErrorKind::NotFound) {
+                    break;
+                }
+            }
+            outcome
+        });
+    let _ = fs::remove_dir_all(&directory);
+    match result {
+        Ok(output) if output.status.success() => say!("Check: the completion compiles."),
+        Ok(output) => {
+            let errors = String::from_utf8_lossy(&output.stderr);
+            let excerpt: Vec<&str> = errors.lines().take(20).collect();
+            say!("Check: the completion does not compile:\n{}", excerpt.join("\n"));
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            warn!("VALIDATE_OUTPUT is set but {} was not found, so the completion was not checked.", commands[0][0]);
+        }
+        Err(error) => warn!("could not check the completion: {}", error),
+    }
+}
+
+// The code inside each triple-backtick fence, without the fences or their language tags
+pub(crate) fn extract_code_blocks(response: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in response.lines() {
+        if line.trim_start().starts_with("```") {
+            match current.take() {
+                Some(lines) => blocks.push(lines.join("\n")),
+                None => current = Some(Vec::new()),
+            }
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line);
+        }
+    }
+    blocks
+}
+
+// A unified diff with the old and new line numbers of every line; empty when nothing changed
+pub(crate) fn render_diff(original: &str, changed: &str, color: bool) -> String {
+    let diff = TextDiff::from_lines(original, changed);
+    let mut output = String::new();
+    for (index, group) in diff.grouped_ops(3).iter().enumerate() {
+        if index > 0 {
+            output.push_str("...\n");
+        }
+        for change in group.iter().flat_map(|op| diff.iter_changes(op)) {
+            let (marker, ansi) = match change.tag() {
+                ChangeTag::Delete => ('-', "\x1b[31m"),
+                ChangeTag::Insert => ('+', "\x1b[32m"),
+                ChangeTag::Equal => (' ', ""),
+            };
+            let old_line = change.old_index().map(|line| (line + 1).to_string()).unwrap_or_default();
+            let new_line = change.new_index().map(|line| (line + 1).to_string()).unwrap_or_default();
+            let line = format!("{:>4} {:>4} {}{}", old_line, new_line, marker, change.value().trim_end_matches(['\r', '\n']));
+            if color && !ansi.is_empty() {
+                output.push_str(&format!("{}{}\x1b[0m\n", ansi, line));
+            } else {
+                output.push_str(&format!("{}\n", line));
+            }
+        }
+    }
+    output
+}
+
+pub(crate) fn build_migration_prompt(prompts: &PromptTemplates, specified_language: &str, code_content: &str, change: &str) -> String {
+    prompts.render("migrate", &[("language", specified_language), ("change", change), ("code", code_content)])
+}
+
+// One mode's request: what to send, where to cache the answer and how to check it
+pub(crate) struct ModeRequest {
+    pub(crate) mode: String,
+    pub(crate) language: String,
+    pub(crate) system_prompt: SystemPrompt,
+    pub(crate) cache_key: String,
+    pub(crate) prompt: String,
+    pub(crate) settings: GenerationSettings,
+    pub(crate) validator: Option<Box<dyn ResponseValidator>>,
+}
+
+impl ModeRequest {
+    // Cached under the prompt itself, as the original modes always were
+    pub(crate) fn new(config: &Config, mode: &str, language: &str, prompt: String, max_tokens: u32) -> ModeRequest {
+        ModeRequest {
+            mode: mode.to_string(),
+            language: language.to_string(),
+            system_prompt: system_prompt_for(config, mode, language),
+            cache_key: prompt.clone(),
+            prompt,
+            settings: GenerationSettings::for_mode(config, mode, language, max_tokens),
+            validator: None,
+        }
+    }
+
+    // Cached under `<mode>:<prompt>` so modes can share a prompt without sharing answers
+    pub(crate) fn prefixed(config: &Config, mode: &str, language: &str, prompt: String, max_tokens: u32) -> ModeRequest {
+        let cache_key = format!("{}:{}", mode, prompt);
+        ModeRequest { cache_key, ..ModeRequest::new(config, mode, language, prompt, max_tokens) }
+    }
+
+    // Hashes `input` into the cache key too, so changing it invalidates the answer even when a
+    // PROMPTS_FILE template leaves it out of the prompt
+    pub(crate) fn keyed_on(self, input: &str) -> ModeRequest {
+        ModeRequest { cache_key: format!("{:016x}:{}", fnv1a_hash(input), self.cache_key), ..self }
+    }
+
+    pub(crate) fn with_validator(self, validator: impl ResponseValidator + 'static) -> ModeRequest {
+        ModeRequest { validator: Some(Box::new(validator)), ..self }
+    }
+
+    // Overridden system prompts are fingerprinted into the key so editing one invalidates stale answers,
+    // while the default leaves keys untouched and existing entries keep matching
+    pub(crate) fn full_cache_key(&self) -> String {
+        if self.system_prompt.is_override {
+            format!("{}#system:{:016x}", self.cache_key, fnv1a_hash(&self.system_prompt.text))
+        } else {
+            self.cache_key.clone()
+        }
+    }
+}
+
+// Sampling parameters for one request
+#[derive(Debug, Clone)]
+pub(crate) struct GenerationSettings {
+    pub(crate) model: Option<String>, // Required by OpenAI-style endpoints; Azure deployments name the model in the URL
+    pub(crate) temperature: f32,
+    pub(crate) top_p: f32,
+    pub(crate) max_tokens: u32,
+    pub(crate) choices: u32, // How many alternatives to ask for; more than one means choosing between them
+}
+
+impl GenerationSettings {
+    // MODEL, TEMPERATURE, TOP_P and MAX_TOKENS apply to every mode and `<NAME>_<MODE>` to one;
+    // without either, the mode's own token limit is used, scaled for how verbose the language tends to be.
+    // `MAX_TOKENS_<LANGUAGE>` sits between the two: it beats MAX_TOKENS but not `MAX_TOKENS_<MODE>`
+    pub(crate) fn for_mode(config: &Config, mode: &str, language: &str, default_max_tokens: u32) -> GenerationSettings {
+        let scaled_max_tokens = (default_max_tokens as f64 * language_token_factor(language)).round() as u32;
+        let max_tokens = config.setting("MAX_TOKENS", scaled_max_tokens);
+        let max_tokens = config.setting(&format!("MAX_TOKENS_{}", language_variable_name(language)), max_tokens);
+        GenerationSettings {
+            model: [format!("MODEL_{}", mode.to_ascii_uppercase()), "MODEL".to_string()].iter()
+                .filter_map(|name| config.value(name))
+                .find(|model| !model.trim().is_empty())
+                .map(str::to_string),
+            temperature: config.mode_setting("TEMPERATURE", mode, 0.7),
+            top_p: config.mode_setting("TOP_P", mode, 0.95),
+            max_tokens: config.setting(&format!("MAX_TOKENS_{}", mode.to_ascii_uppercase()), max_tokens),
+            choices: config.mode_setting("CHOICES", mode, 1).max(1),
+        }
+    }
+}
+
+// Resolves the system prompt for a mode: a non-empty SYSTEM_PROMPT_<MODE> override wins over SYSTEM_PROMPT,
+// which wins over the built-in default
+pub(crate) fn system_prompt_for(config: &Config, mode: &str, language: &str) -> SystemPrompt {
+    let variable = format!("SYSTEM_PROMPT_{}", mode.to_ascii_uppercase());
+    match config.value(&variable) {
+        Some(text) if !text.trim().is_empty() => SystemPrompt { text: text.to_string(), is_override: true },
+        Some(_) => {
+            warn!("{} is empty, using the default system prompt.", variable);
+            default_system_prompt(config, language)
+        }
+        None => default_system_prompt(config, language),
+    }
+}
+
+// The session-wide system prompt; `{language}` in SYSTEM_PROMPT is replaced with the chosen language.
+// It is left out of cache keys, so entries cached before it was set still match
+pub(crate) fn default_system_prompt(config: &Config, language: &str) -> SystemPrompt {
+    let text = match config.value("SYSTEM_PROMPT") {
+        Some(text) if !text.trim().is_empty() => text.replace("{language}", language),
+        _ => format!("You are an expert {} software engineer.", language),
+    };
+    SystemPrompt { text, is_override: false }
+}
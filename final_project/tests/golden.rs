@@ -0,0 +1,17 @@
+use final_project::{Assistant, Message};
+use std::fs;
+
+// Regenerated with `final_project --mode explain --lang rust --file <the code below> --golden tests/golden/explanation.json --force`,
+// run somewhere without a .env, prompts file or SYSTEM_PROMPT
+const CODE: &str = "fn inc(x: i32) -> i32 {\n    x + 1\n}\n";
+
+#[test]
+fn the_explanation_prompt_matches_its_golden_file() {
+    let golden: Vec<Vec<Message>> = serde_json::from_str(&fs::read_to_string("tests/golden/explanation.json").unwrap()).unwrap();
+    let assistant = Assistant::new("http://127.0.0.1:9/v1/chat/completions", None, "rust").unwrap();
+
+    let messages = assistant.messages("explanation", &[CODE]).unwrap();
+
+    // The library builds the same messages as a `--golden` run of the explain mode
+    assert_eq!(golden, vec![messages]);
+}
@@ -0,0 +1,78 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+// An empty working directory, so no .env or config.toml of the checkout is picked up
+fn scratch_dir(name: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!("final_project-quiet-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("main.rs"), "fn main() {}\n").unwrap();
+    directory
+}
+
+fn run_explain(directory: &PathBuf, endpoint: &str, extra_args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_final_project"))
+        .args(["--mode", "explain", "--lang", "Rust", "--file", "main.rs", "--no-cache"])
+        .args(extra_args)
+        .current_dir(directory)
+        .env_clear()
+        .env("PATH", env::var_os("PATH").unwrap_or_default())
+        .env("HOME", directory)
+        .env("API_ENDPOINT", endpoint)
+        .env("HISTORY_FILE", directory.join("history.jsonl"))
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+// Answers a single chat completions request with `answer`
+fn serve_once(answer: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut content_length = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            if let Some(length) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                content_length = length.trim().parse().unwrap();
+            }
+            line.clear();
+        }
+        reader.read_exact(&mut vec![0; content_length]).unwrap();
+        let body = format!(r#"{{"choices":[{{"message":{{"role":"assistant","content":"{}"}},"finish_reason":"stop","index":0}}]}}"#, answer);
+        let mut stream = stream;
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+    });
+    endpoint
+}
+
+#[test]
+fn quiet_mode_leaves_only_the_response_on_stdout() {
+    let directory = scratch_dir("response");
+    let output = run_explain(&directory, &serve_once("It does nothing."), &["--quiet"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "It does nothing.");
+    // The notices still go somewhere, just not into the captured answer
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Took "));
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn a_quiet_dry_run_prints_only_the_payload() {
+    let directory = scratch_dir("dry-run");
+    let output = run_explain(&directory, "http://127.0.0.1:9/v1/chat/completions", &["--quiet", "--dry-run"]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let payload: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(payload["messages"][0]["role"], "system");
+    assert!(payload["messages"][1]["content"].as_str().unwrap().contains("fn main() {}"));
+    fs::remove_dir_all(&directory).unwrap();
+}